@@ -5,12 +5,15 @@ use bitflags::bitflags;
 use byteorder::{LittleEndian, ReadBytesExt};
 use byteorder_pack::UnpackFrom;
 use std::{
-    cell::RefCell,
+    cell::{OnceCell, RefCell},
+    collections::{HashMap, HashSet},
     fs::File,
     io::{self, BufWriter, Read, Seek, Write},
-    ops::BitAnd,
-    path::PathBuf,
+    ops::{BitAnd, Range},
+    path::{Path, PathBuf},
     rc::Rc,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use thiserror::Error;
 
@@ -19,6 +22,10 @@ pub type Result<T> = std::result::Result<T, VFFError>;
 const FAT16_MAX_CLUSTERS: u32 = 0xfff5;
 const FAT12_MAX_CLUSTERS: u32 = 0xff5;
 const EXPECTED_FILE_MAGIC: [u8; 4] = [b'V', b'F', b'F', b' '];
+/// The root directory size in cdb.vff, room for 128 entries
+pub const DEFAULT_ROOT_DIR_SIZE: u64 = 0x1000;
+/// The header stores the cluster size in units of this many bytes
+pub const DEFAULT_CLUSTER_SIZE_MULTIPLIER: u16 = 16;
 
 #[derive(Error, Debug)]
 pub enum VFFError {
@@ -26,14 +33,28 @@ pub enum VFFError {
     IOErr(#[from] io::Error),
     #[error("Error: {0}")]
     Other(String),
-    #[error("invalid data in {context}: (expected {expected}, found {found})")]
+    #[error(
+        "invalid data in {context}{}: (expected {expected}, found {found})",
+        .offset.map(|offset| format!(" at {offset:#x}")).unwrap_or_default()
+    )]
     InvalidData {
         context: String,
         expected: String,
         found: String,
+        /// Where in the input the bad data was, when it's known
+        offset: Option<u64>,
     },
 }
 
+impl From<VFFError> for io::Error {
+    fn from(err: VFFError) -> Self {
+        match err {
+            VFFError::IOErr(e) => e,
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 #[non_exhaustive]
 pub enum SupportedFAT {
@@ -53,32 +74,135 @@ impl SupportedFAT {
     }
 }
 
+/// How a FAT entry is being used, see `VFF::fat_map`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClusterState {
+    Free,
+    /// Links to the next cluster of a chain
+    Used,
+    Bad,
+    /// The last cluster of a chain
+    Eoc,
+    /// Entries 0 and 1, which don't stand for a cluster
+    Reserved,
+}
+
 #[derive(Debug)]
 pub struct FAT {
     fattype: SupportedFAT,
     clusters: Vec<u16>,
+    cluster_count: u32,
+    /// Where the table starts in the input, if it was read as part of a VFF
+    offset: Option<u64>,
 }
 
 impl FAT {
     pub fn new(fd: &mut impl Read, header: &VFFHeader) -> Result<Self> {
         let cluster_count = header.cluster_count;
-        let cluster_size = header.cluster_size as u32;
-        let fattype: SupportedFAT;
-        let fatsize: u32;
+        let fattype = SupportedFAT::FAT16;
+        let buf_size = Self::fat16_table_size(header)?;
+        // buf_size is in bytes, each FAT16 entry is two
+        let entries = buf_size as usize / 2;
+        let mut clusters = Vec::with_capacity(entries);
+        clusters.resize_with(entries, Default::default);
+        fd.read_u16_into::<LittleEndian>(clusters.as_mut_slice())?;
+        Ok(Self {
+            fattype,
+            clusters,
+            cluster_count,
+            offset: None,
+        })
+    }
+
+    /// Check that entry 0 holds a media descriptor in its low byte and entry 1 an end of chain
+    /// marker. FAT16 drivers may clear the top two bits of entry 1 to record an unclean
+    /// shutdown or a disk error, so those are ignored.
+    fn check_reserved(&self) -> Result<()> {
+        let (media, eoc) = (self.get_cluster(0)?, self.get_cluster(1)?);
+        if matches!(media & 0xff, 0xf0 | 0xf8..=0xff) && self.is_last(eoc | 0xc000) {
+            return Ok(());
+        }
+        Err(VFFError::InvalidData {
+            context: "Checking the reserved FAT entries".to_owned(),
+            expected: "A media descriptor and an end of chain marker".to_owned(),
+            found: format!("{media:04x} {eoc:04x}"),
+            offset: self.entry_offset(0),
+        })
+    }
+
+    /// Where the entry for `cluster` is in the input
+    fn entry_offset(&self, cluster: u32) -> Option<u64> {
+        self.offset
+            .map(|offset| offset + 2 * self.fattype.mask(cluster) as u64)
+    }
+
+    /// Each copy of the table is padded out to a whole number of clusters
+    fn table_size(fatsize: u32, cluster_size: u32) -> u32 {
+        (fatsize + cluster_size - 1) & !(cluster_size - 1)
+    }
+
+    /// The size in bytes of each FAT copy `header` describes, checking that it's FAT16 first
+    /// so a huge cluster count can't overflow
+    fn fat16_table_size(header: &VFFHeader) -> Result<u32> {
+        let cluster_count = header.cluster_count;
         if cluster_count > FAT16_MAX_CLUSTERS {
             return Err(VFFError::Other("FAT 32 is not supported".to_owned()));
         }
-        if cluster_count > FAT12_MAX_CLUSTERS {
-            fattype = SupportedFAT::FAT16;
-            fatsize = cluster_count * 2;
-        } else {
+        if cluster_count <= FAT12_MAX_CLUSTERS {
             return Err(VFFError::Other("FAT12 is not supported".to_owned()));
         }
-        let buf_size = (fatsize + cluster_size - 1) & !(cluster_size - 1);
-        let mut clusters = Vec::with_capacity(buf_size as usize);
-        clusters.resize_with(buf_size as usize, Default::default);
-        fd.read_u16_into::<LittleEndian>(clusters.as_mut_slice())?;
-        Ok(Self { fattype, clusters })
+        Ok(Self::table_size(
+            cluster_count * 2,
+            header.cluster_size as u32,
+        ))
+    }
+
+    pub fn fattype(&self) -> &SupportedFAT {
+        &self.fattype
+    }
+
+    /// The raw table, including the two reserved entries and the padding after the last cluster
+    pub fn as_slice(&self) -> &[u16] {
+        &self.clusters
+    }
+
+    /// The values of every data cluster entry, skipping the two reserved entries and the
+    /// padding after the last cluster
+    fn data_cluster_values(&self) -> impl Iterator<Item = u32> + '_ {
+        let end = (self.cluster_count as usize).min(self.clusters.len());
+        self.clusters
+            .get(2..end)
+            .unwrap_or_default()
+            .iter()
+            .map(|x| *x as u32)
+    }
+
+    pub fn free_cluster_count(&self) -> u32 {
+        self.data_cluster_values()
+            .filter(|x| self.is_available(*x))
+            .count() as u32
+    }
+
+    /// Allocated clusters, including the final cluster of each chain
+    pub fn used_cluster_count(&self) -> u32 {
+        self.data_cluster_values()
+            .filter(|x| self.is_used(*x) || self.is_last(*x))
+            .count() as u32
+    }
+
+    pub fn bad_cluster_count(&self) -> u32 {
+        self.data_cluster_values()
+            .filter(|x| self.is_bad(*x))
+            .count() as u32
+    }
+
+    /// The number of every data cluster marked bad, in order
+    pub fn bad_clusters(&self) -> Vec<u32> {
+        (2..)
+            .zip(self.data_cluster_values())
+            .filter(|(_, x)| self.is_bad(*x))
+            .map(|(cluster, _)| cluster)
+            .collect()
     }
 
     fn get_fat16(&self, index: usize) -> Result<u32> {
@@ -96,6 +220,7 @@ impl FAT {
                 context: "get_cluster FAT16".to_owned(),
                 expected,
                 found,
+                offset: None,
             })
         }
     }
@@ -109,10 +234,74 @@ impl FAT {
         }
     }
 
-    pub fn is_available(x: u32) -> bool {
-        x == 0
+    fn set_cluster(&mut self, index: u32, value: u32) -> Result<()> {
+        let index = self.fattype.mask(index);
+        let len = self.clusters.len();
+        let Some(entry) = self.clusters.get_mut(index) else {
+            return Err(VFFError::InvalidData {
+                context: "set_cluster FAT16".to_owned(),
+                expected: "Indexing into the cluster data at a valid location".to_owned(),
+                found: format!("Asked for: {index} Cluster len: {len}"),
+                offset: None,
+            });
+        };
+        *entry = value as u16;
+        Ok(())
+    }
+
+    /// Link `clusters_needed` free clusters into a new chain, lowest first, and return it. Only
+    /// this copy of the table is changed, see `VFF::allocate_chain` to update a VFF.
+    pub fn allocate_chain(&mut self, clusters_needed: usize) -> Result<Vec<u32>> {
+        let end = (self.cluster_count as usize).min(self.clusters.len());
+        let chain: Vec<u32> = (2..end as u32)
+            .filter(|&cluster| self.is_available(self.clusters[cluster as usize] as u32))
+            .take(clusters_needed)
+            .collect();
+        if chain.len() < clusters_needed {
+            return Err(VFFError::Other(format!(
+                "Not enough free clusters: {clusters_needed} needed, {} available",
+                chain.len()
+            )));
+        }
+        self.link_chain(&chain)?;
+        Ok(chain)
+    }
+
+    /// Like `allocate_chain`, but the clusters are one contiguous run, the lowest that fits
+    pub fn allocate_contiguous(&mut self, clusters_needed: usize) -> Result<Vec<u32>> {
+        let end = (self.cluster_count as usize).min(self.clusters.len()) as u32;
+        let mut run_start = 2;
+        for cluster in 2..end {
+            if !self.is_available(self.clusters[cluster as usize] as u32) {
+                run_start = cluster + 1;
+            } else if (cluster + 1 - run_start) as usize == clusters_needed {
+                break;
+            }
+        }
+        if clusters_needed > 0 && (end.saturating_sub(run_start) as usize) < clusters_needed {
+            return Err(VFFError::Other(format!(
+                "No run of {clusters_needed} free clusters"
+            )));
+        }
+        let chain: Vec<u32> = (run_start..).take(clusters_needed).collect();
+        self.link_chain(&chain)?;
+        Ok(chain)
+    }
+
+    /// Point each cluster of `chain` at the next, ending it with an end of chain marker
+    fn link_chain(&mut self, chain: &[u32]) -> Result<()> {
+        for (i, &cluster) in chain.iter().enumerate() {
+            let next = chain.get(i + 1).copied().unwrap_or(0xffff);
+            self.set_cluster(cluster, next)?;
+        }
+        Ok(())
     }
 
+    /// Whether `x` marks a free cluster. Every entry value is exactly one of available, used,
+    /// bad or last.
+    pub fn is_available(&self, x: u32) -> bool {
+        x == 0
+    }
     pub fn is_used(&self, x: u32) -> bool {
         let reserved = self.fattype.get_reserved_marker();
         0x1 <= x && x < reserved
@@ -120,8 +309,11 @@ impl FAT {
     pub fn is_bad(&self, x: u32) -> bool {
         x == self.fattype.get_reserved_marker() + 7
     }
+    /// Whether `x` ends a chain. Besides the standard end of chain values from `reserved + 8`
+    /// up, some formatters end chains with one of the reserved values below them, so those are
+    /// accepted too. Only the bad cluster marker is excluded.
     pub fn is_last(&self, x: u32) -> bool {
-        self.fattype.get_reserved_marker() + 8 <= x
+        self.fattype.get_reserved_marker() <= x && !self.is_bad(x)
     }
     pub fn get_chain(&self, start: u32) -> Result<Vec<u32>> {
         let mut chain: Vec<u32> = Vec::new();
@@ -129,41 +321,274 @@ impl FAT {
         while self.is_used(current) {
             chain.push(current);
             current = self.get_cluster(current)?;
+            if chain.len() > self.cluster_count as usize {
+                return Err(VFFError::InvalidData {
+                    context: "FAT chain parsing".to_owned(),
+                    expected: "A chain that doesn't loop back on itself".to_owned(),
+                    found: format!("A loop in the chain starting at {start:04x}"),
+                    offset: chain.last().and_then(|&last| self.entry_offset(last)),
+                });
+            }
         }
         if !self.is_last(current) {
             return Err(VFFError::InvalidData {
                 context: "FAT chain parsing".to_owned(),
                 expected: "The first unused cluster in the chain should satisfy is_last".to_owned(),
                 found: format!("False, the cluster reads: {current:04x}"),
+                offset: chain.last().and_then(|&last| self.entry_offset(last)),
             });
         }
         Ok(chain)
     }
+
+    /// Like `get_chain`, but instead of failing on a broken chain, stop at the last cluster that
+    /// can be trusted and describe the problem
+    pub fn get_chain_lenient(&self, start: u32) -> (Vec<u32>, Option<Warning>) {
+        let mut chain: Vec<u32> = Vec::new();
+        let mut current = start;
+        while self.is_used(current) {
+            match self.get_cluster(current) {
+                Ok(next) => chain.push(std::mem::replace(&mut current, next)),
+                Err(_) => {
+                    return (
+                        chain,
+                        Some(Warning::OutOfRange {
+                            start,
+                            cluster: current,
+                        }),
+                    )
+                }
+            }
+            // Longer than the FAT means some cluster repeats
+            if chain.len() > self.cluster_count as usize {
+                let mut seen = std::collections::HashSet::new();
+                let end = chain
+                    .iter()
+                    .position(|c| !seen.insert(*c))
+                    .unwrap_or(chain.len());
+                chain.truncate(end);
+                let cluster = chain.last().copied().unwrap_or(start);
+                return (chain, Some(Warning::Loop { start, cluster }));
+            }
+        }
+        if self.is_bad(current) {
+            // The last cluster we pushed is the one marked bad, don't read it
+            let cluster = chain.pop().unwrap_or(start);
+            return (chain, Some(Warning::BadCluster { start, cluster }));
+        }
+        if !self.is_last(current) {
+            let cluster = chain.last().copied().unwrap_or(start);
+            let warning = Warning::InvalidTerminator {
+                start,
+                cluster,
+                value: current,
+            };
+            return (chain, Some(warning));
+        }
+        (chain, None)
+    }
+}
+
+/// A problem that a lenient read worked around instead of failing, or that opening the VFF
+/// noticed but could carry on past
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// The chain from `start` reached `cluster`, which is marked bad
+    BadCluster { start: u32, cluster: u32 },
+    /// The chain from `start` continues from `cluster` to `value`, which is neither in use nor
+    /// an end of chain marker
+    InvalidTerminator {
+        start: u32,
+        cluster: u32,
+        value: u32,
+    },
+    /// The chain from `start` reached `cluster`, which is past the end of the FAT
+    OutOfRange { start: u32, cluster: u32 },
+    /// The chain from `start` loops back on itself after `cluster`
+    Loop { start: u32, cluster: u32 },
+    /// The file starting at `start` is `size` bytes long, but its chain has `clusters` clusters
+    SizeMismatch {
+        start: u32,
+        size: u32,
+        clusters: u32,
+    },
+    /// The directory at `path` was given `len` bytes, which isn't a whole number of entries.
+    /// The partial entry at the end was dropped.
+    UnalignedDirectory { path: String, len: usize },
+    /// The header's `volume_size` isn't a whole number of `cluster_size` clusters. The partial
+    /// cluster at the end can't be addressed, so it isn't counted in `cluster_count`.
+    PartialCluster { volume_size: u32, cluster_size: u16 },
+    /// The two reserved entries at the start of a FAT copy, which should hold a media
+    /// descriptor and an end of chain marker, are `values` instead
+    ReservedEntries { copy: FatCopy, values: [u32; 2] },
+    /// The directory at `path` starts at `start`, the same cluster as a directory above it,
+    /// so it wasn't entered
+    DirectoryCycle { path: String, start: u32 },
+    /// A dump wrote the entry at `path` as `name`, because another entry in the same
+    /// directory already took its name, ignoring case
+    NameCollision { path: String, name: String },
+    /// A dump or recovery wrote the entry at `path` as `name`, because its own name is `.` or
+    /// `..`, or has a `/`, `\` or NUL in it, so it can't be used as a file name as it is
+    UnsafeName { path: String, name: String },
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadCluster { start, cluster } => {
+                write!(f, "chain {start:04x} stopped at bad cluster {cluster:04x}")
+            }
+            Self::InvalidTerminator {
+                start,
+                cluster,
+                value,
+            } => write!(
+                f,
+                "chain {start:04x} stopped at cluster {cluster:04x}, which links to {value:04x}"
+            ),
+            Self::OutOfRange { start, cluster } => write!(
+                f,
+                "chain {start:04x} stopped at cluster {cluster:04x}, which is past the end of the FAT"
+            ),
+            Self::Loop { start, cluster } => {
+                write!(f, "chain {start:04x} loops back on itself after cluster {cluster:04x}")
+            }
+            Self::SizeMismatch {
+                start,
+                size,
+                clusters,
+            } => write!(
+                f,
+                "chain {start:04x} has {clusters} clusters, which doesn't fit a {size:#x} byte file"
+            ),
+            Self::UnalignedDirectory { path, len } => write!(
+                f,
+                "directory {path:?} is {len:#x} bytes, which isn't a multiple of 32"
+            ),
+            Self::PartialCluster {
+                volume_size,
+                cluster_size,
+            } => write!(
+                f,
+                "the volume size {volume_size:#x} isn't a multiple of the cluster size {cluster_size:#x}, ignoring the last {:#x} bytes",
+                volume_size % *cluster_size as u32
+            ),
+            Self::ReservedEntries { copy, values } => write!(
+                f,
+                "the reserved entries of the {} FAT are {:04x} {:04x}, not a media descriptor and an end of chain marker",
+                match copy {
+                    FatCopy::Primary => "primary",
+                    FatCopy::Backup => "backup",
+                },
+                values[0],
+                values[1]
+            ),
+            Self::DirectoryCycle { path, start } => write!(
+                f,
+                "directory {path:?} starts at cluster {start:04x}, which is one of its own parents"
+            ),
+            Self::NameCollision { path, name } => write!(
+                f,
+                "dumped {path:?} as {name:?}, another entry with the same name ignoring case was already dumped"
+            ),
+            Self::UnsafeName { path, name } => write!(
+                f,
+                "wrote {path:?} as {name:?}, its name can't be used as a file name"
+            ),
+        }
+    }
+}
+
+/// Which of the two FAT copies stored in a VFF was used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatCopy {
+    Primary,
+    Backup,
+}
+
+/// How the bytes of 8.3 names are turned into text. Long names are always UTF-16. Some
+/// encodings need a feature, so matches on this need a wildcard arm.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NameEncoding {
+    /// UTF-8, with invalid bytes replaced by U+FFFD
+    #[default]
+    Utf8Lossy,
+    /// Shift JIS, which Japanese consoles use for their 8.3 names
+    #[cfg(feature = "encoding_rs")]
+    ShiftJis,
+}
+
+impl NameEncoding {
+    fn decode(self, bytes: &[u8]) -> String {
+        let text = match self {
+            NameEncoding::Utf8Lossy => String::from_utf8_lossy(bytes),
+            #[cfg(feature = "encoding_rs")]
+            NameEncoding::ShiftJis => encoding_rs::SHIFT_JIS.decode_without_bom_handling(bytes).0,
+        };
+        text.trim_end().to_owned()
+    }
+}
+
+/// The byte order of the VFF header fields
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endianness {
+    /// What the Wii writes
+    #[default]
+    Big,
+    Little,
 }
 
 #[derive(Debug)]
 pub struct VFFHeader {
+    /// The word after the magic. Its meaning isn't known yet.
+    pub unknown: u32,
     pub volume_size: u32,
     pub cluster_size: u16,
+    /// Only whole clusters are counted, see `Warning::PartialCluster`
     pub cluster_count: u32,
+    /// The 0x10 bytes between the header and the first FAT, as read. Usually zero padding or a
+    /// copy of the header, but what the Wii puts there otherwise isn't known yet.
+    pub raw_preamble2: [u8; 0x10],
 }
 
-fn check_header(vff_header: [u8; 0x10]) -> Result<VFFHeader> {
+/// `base_offset` is where the header was read from, for errors
+fn check_header(
+    vff_header: [u8; 0x10],
+    preamble: [u8; 0x10],
+    base_offset: u64,
+    endianness: Endianness,
+    multiplier: u16,
+) -> Result<VFFHeader> {
     let mut cursor = std::io::Cursor::new(vff_header);
-    let (magic, _unknown_header_entry, volume_size, cluster_size) =
-        <([u8; 4], u32, u32, u16)>::unpack_from_be(&mut cursor)?;
-    let cluster_size = cluster_size.checked_mul(16).ok_or_else(|| {
-        return VFFError::InvalidData {
-            context: "Checking VFF Header - Compute cluster size".to_owned(),
-            expected: "cluster_size * 16 should not overflow".to_owned(),
-            found: "Overflow detected".to_owned(),
-        };
-    })?;
+    let (magic, unknown, volume_size, cluster_size) = match endianness {
+        Endianness::Big => <([u8; 4], u32, u32, u16)>::unpack_from_be(&mut cursor)?,
+        Endianness::Little => <([u8; 4], u32, u32, u16)>::unpack_from_le(&mut cursor)?,
+    };
+    let cluster_size =
+        cluster_size
+            .checked_mul(multiplier)
+            .ok_or_else(|| VFFError::InvalidData {
+                context: "Checking VFF Header - Compute cluster size".to_owned(),
+                expected: format!("cluster_size * {multiplier} should not overflow"),
+                found: "Overflow detected".to_owned(),
+                offset: Some(base_offset + 0xc),
+            })?;
     if cluster_size == 0 {
         return Err(VFFError::InvalidData {
             context: "Check VFF Header".to_owned(),
             expected: "Cluster size != 0".to_owned(),
             found: "0".to_owned(),
+            offset: Some(base_offset + 0xc),
+        });
+    }
+    // The FAT size is rounded up to whole clusters with a mask, which needs a power of two
+    if !cluster_size.is_power_of_two() {
+        return Err(VFFError::InvalidData {
+            context: "Check VFF Header".to_owned(),
+            expected: "Cluster size is a power of two".to_owned(),
+            found: format!("{cluster_size:#x}"),
+            offset: Some(base_offset + 0xc),
         });
     }
     if magic != EXPECTED_FILE_MAGIC {
@@ -171,23 +596,49 @@ fn check_header(vff_header: [u8; 0x10]) -> Result<VFFHeader> {
             context: "Check VFF Header: parsing file magic".to_owned(),
             expected: format!("{EXPECTED_FILE_MAGIC:?}"),
             found: format!("{magic:?}"),
+            offset: Some(base_offset),
         });
     }
     Ok(VFFHeader {
+        unknown,
         volume_size,
         cluster_size,
         cluster_count: volume_size / cluster_size as u32,
+        raw_preamble2: preamble,
+    })
+}
+
+/// Check the 0x10 bytes between the header and the first FAT. They're expected to be either
+/// zero padding or a second copy of the header, anything else suggests this isn't a VFF.
+fn check_preamble(vff_header: &[u8; 0x10], preamble: &[u8; 0x10], base_offset: u64) -> Result<()> {
+    if preamble.iter().all(|b| *b == 0) || preamble == vff_header {
+        return Ok(());
+    }
+    Err(VFFError::InvalidData {
+        context: "Check VFF Header: the preamble after the header".to_owned(),
+        expected: "Zero padding or a copy of the header".to_owned(),
+        found: format!("{preamble:02x?}"),
+        offset: Some(base_offset + 0x10),
     })
 }
 
 bitflags! {
-    struct DirectoryFlags: u8 {
+    /// The attribute byte of a directory entry
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct DirectoryFlags: u8 {
+        /// Read only
         const A_R   =  1;
+        /// Hidden
         const A_H   =  2;
+        /// System
         const A_S   =  4;
+        /// Volume label
         const A_VL  =  8;
+        /// Directory
         const A_DIR = 16;
+        /// Archive, set when a file is modified
         const A_A   = 32;
+        /// Device
         const A_DEV = 64;
     }
 }
@@ -200,9 +651,10 @@ impl BitAnd<DirectoryFlags> for u8 {
     }
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-struct ParsedFATEntry {
+/// A 32 byte directory entry as it's stored, for tools that need the fields `DirectoryEntry`
+/// doesn't carry
+#[derive(Debug, Clone)]
+pub struct ParsedFATEntry {
     pub name: [u8; 8],
     pub ext: [u8; 3],
     pub attr: u8,
@@ -217,6 +669,7 @@ struct ParsedFATEntry {
     pub start: u16,
     pub size: u32,
     pub deleted: bool,
+    pub long_name: Option<String>,
 }
 
 impl ParsedFATEntry {
@@ -242,19 +695,239 @@ impl ParsedFATEntry {
             start,
             size,
             deleted: false,
+            long_name: None,
         })
     }
+    /// The name as it was meant to be written. A real leading 0xE5 (a common Shift JIS lead
+    /// byte) is stored as 0x05 so it doesn't read as the deleted marker.
+    pub fn name_bytes(&self) -> [u8; 8] {
+        let mut name = self.name;
+        if name[0] == 0x05 {
+            name[0] = 0xe5;
+        }
+        name
+    }
+    /// Whether this is the `.` entry at the start of a subdirectory, which refers to the
+    /// subdirectory itself
+    pub fn is_dot(&self) -> bool {
+        self.name == *b".       " && self.ext == *b"   "
+    }
+    /// Whether this is the `..` entry at the start of a subdirectory, which refers to its parent
+    pub fn is_dotdot(&self) -> bool {
+        self.name == *b"..      " && self.ext == *b"   "
+    }
+    fn is_dot_or_dotdot(&self) -> bool {
+        self.is_dot() || self.is_dotdot()
+    }
     pub fn nice_name(&self) -> String {
-        String::from_utf8_lossy(&self.name).trim_end().to_owned()
+        self.name_in(NameEncoding::default())
     }
     pub fn nice_extension(&self) -> String {
-        String::from_utf8_lossy(&self.ext).trim_end().to_owned()
+        self.extension_in(NameEncoding::default())
+    }
+    pub fn nice_short_name(&self) -> String {
+        self.short_name_in(NameEncoding::default())
     }
     pub fn nice_full_name(&self) -> String {
+        self.full_name_in(NameEncoding::default())
+    }
+    /// Like `nice_name`, decoding the name bytes with `encoding`
+    pub fn name_in(&self, encoding: NameEncoding) -> String {
+        encoding.decode(&self.name_bytes())
+    }
+    pub fn extension_in(&self, encoding: NameEncoding) -> String {
+        encoding.decode(&self.ext)
+    }
+    pub fn short_name_in(&self, encoding: NameEncoding) -> String {
         if self.attr & DirectoryFlags::A_DIR != 0 {
-            return self.nice_name();
+            return self.name_in(encoding);
+        }
+        self.name_in(encoding) + "." + &self.extension_in(encoding)
+    }
+    pub fn full_name_in(&self, encoding: NameEncoding) -> String {
+        match &self.long_name {
+            Some(long_name) => long_name.clone(),
+            None => self.short_name_in(encoding),
+        }
+    }
+    /// Long name slots also have the volume label bit set, so this only holds for a real label
+    fn is_volume_label(&self) -> bool {
+        self.attr & 0xf != 0xf && self.attr & DirectoryFlags::A_VL != 0
+    }
+    fn short_name_checksum(&self) -> u8 {
+        self.name
+            .iter()
+            .chain(self.ext.iter())
+            .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+    }
+}
+
+/// Collects the VFAT long file name slots that precede an 8.3 entry
+#[derive(Default)]
+struct LongNameBuilder {
+    slots: Vec<[u8; 32]>,
+}
+
+impl LongNameBuilder {
+    fn push(&mut self, slot: &[u8; 32]) {
+        // The last part of the name is stored first and flagged with 0x40. Deleting overwrites
+        // that byte with 0xE5, so the slots of a deleted name only start over after live ones.
+        let starts_over = match slot[0] {
+            0xe5 => self.slots.iter().any(|s| s[0] != 0xe5),
+            ordinal => ordinal & 0x40 != 0,
+        };
+        if starts_over {
+            self.slots.clear();
+        }
+        self.slots.push(*slot);
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+    }
+
+    /// Assemble the long name for `entry`, or None if the pending slots don't belong to it
+    fn finish(&mut self, entry: &ParsedFATEntry) -> Option<String> {
+        let slots = std::mem::take(&mut self.slots);
+        let checksum = slots.first()?[13];
+        let valid = match entry.deleted {
+            // The ordinals and the first letter of the short name are gone, so all that's left
+            // to check is that the slots agree with each other
+            true => slots.iter().all(|s| s[0] == 0xe5 && s[13] == checksum),
+            false => {
+                slots[0][0] & 0x40 != 0
+                    && checksum == entry.short_name_checksum()
+                    && slots.iter().rev().enumerate().all(|(i, s)| {
+                        s[0] != 0xe5 && (s[0] & 0x1f) as usize == i + 1 && s[13] == checksum
+                    })
+            }
+        };
+        if !valid {
+            return None;
+        }
+        let mut units: Vec<u16> = Vec::with_capacity(slots.len() * 13);
+        for slot in slots.iter().rev() {
+            // Characters are spread across the name, the bytes after the attribute, and the size field
+            for range in [1..11, 14..26, 28..32] {
+                units.extend(
+                    slot[range]
+                        .chunks_exact(2)
+                        .map(|c| u16::from_le_bytes([c[0], c[1]])),
+                );
+            }
+        }
+        let end = units.iter().position(|&u| u == 0).unwrap_or(units.len());
+        Some(String::from_utf16_lossy(&units[..end]))
+    }
+}
+
+/// A timestamp decoded from the packed FAT date/time fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FatDateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl FatDateTime {
+    /// Decode a FAT date and time. Returns None for an unset (zero) date.
+    pub fn from_raw(date: u16, time: u16) -> Option<Self> {
+        if date == 0 {
+            return None;
+        }
+        Some(FatDateTime {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0xf) as u8,
+            day: (date & 0x1f) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3f) as u8,
+            second: ((time & 0x1f) * 2) as u8,
+        })
+    }
+}
+
+impl FatDateTime {
+    /// Seconds since the Unix epoch. FAT doesn't record a time zone, so this treats the
+    /// timestamp as UTC.
+    pub fn to_unix_timestamp(&self) -> i64 {
+        // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+        let (month, day) = (self.month as i64, self.day as i64);
+        let year = self.year as i64 - (month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+        days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    /// The timestamp as a `SystemTime`, treated as UTC like `to_unix_timestamp`
+    pub fn to_system_time(&self) -> SystemTime {
+        // FAT dates start at 1980, so this never lands before the epoch
+        UNIX_EPOCH + Duration::from_secs(self.to_unix_timestamp() as u64)
+    }
+}
+
+impl std::fmt::Display for FatDateTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EntryMetadata {
+    pub size: u32,
+    pub attributes: u8,
+    pub created: Option<FatDateTime>,
+    pub modified: Option<FatDateTime>,
+    /// FAT only records the date of last access
+    pub accessed: Option<FatDateTime>,
+    pub deleted: bool,
+}
+
+impl EntryMetadata {
+    pub fn flags(&self) -> DirectoryFlags {
+        DirectoryFlags::from_bits_retain(self.attributes)
+    }
+    pub fn is_readonly(&self) -> bool {
+        self.flags().contains(DirectoryFlags::A_R)
+    }
+    pub fn is_hidden(&self) -> bool {
+        self.flags().contains(DirectoryFlags::A_H)
+    }
+    pub fn is_system(&self) -> bool {
+        self.flags().contains(DirectoryFlags::A_S)
+    }
+    pub fn is_volume_label(&self) -> bool {
+        self.flags().contains(DirectoryFlags::A_VL)
+    }
+    pub fn is_archive(&self) -> bool {
+        self.flags().contains(DirectoryFlags::A_A)
+    }
+}
+
+impl From<&ParsedFATEntry> for EntryMetadata {
+    fn from(entry: &ParsedFATEntry) -> Self {
+        // cms holds the creation time in 10ms units, which can carry into the next second
+        let created = FatDateTime::from_raw(entry.cdate, entry.ctime).map(|mut c| {
+            c.second += entry.cms / 100;
+            c
+        });
+        EntryMetadata {
+            size: entry.size,
+            attributes: entry.attr,
+            created,
+            modified: FatDateTime::from_raw(entry.mdate, entry.mtime),
+            accessed: FatDateTime::from_raw(entry.adate, 0),
+            deleted: entry.deleted,
         }
-        self.nice_name() + "." + &self.nice_extension()
     }
 }
 
@@ -262,29 +935,50 @@ impl ParsedFATEntry {
 pub enum DirectoryContent {
     Dir(Directory),
     File(Vec<u8>),
+    /// A file whose bytes haven't been read yet, see `DirectoryEntry::load_bytes`
+    Unloaded,
     NoContent,
 }
 
+/// Where an entry's data lives, so it can be read after the fact
+#[derive(Debug, Clone)]
+struct EntrySource {
+    vff: Rc<RefCell<VFF>>,
+    start: u32,
+}
+
 #[derive(Debug, Clone)]
 pub struct DirectoryEntry {
     path: String,
     name: String,
+    full_name: String,
+    short_name: String,
     content: DirectoryContent,
+    metadata: EntryMetadata,
+    source: Option<EntrySource>,
 }
 
 impl DirectoryEntry {
     pub fn make_dir_entry(path: String, name: String, dir: Directory) -> Self {
         DirectoryEntry {
             path,
+            full_name: name.clone(),
+            short_name: name.clone(),
             name,
             content: DirectoryContent::Dir(dir),
+            metadata: EntryMetadata::default(),
+            source: None,
         }
     }
     pub fn make_file_entry(path: String, name: String, file: Vec<u8>) -> Self {
         DirectoryEntry {
             path,
+            full_name: name.clone(),
+            short_name: name.clone(),
             name,
             content: DirectoryContent::File(file),
+            metadata: EntryMetadata::default(),
+            source: None,
         }
     }
     pub fn make_empty_file_entry(path: String, name: String) -> Self {
@@ -294,7 +988,11 @@ impl DirectoryEntry {
         DirectoryEntry {
             path,
             name: String::with_capacity(0),
+            full_name: String::with_capacity(0),
+            short_name: String::with_capacity(0),
             content: DirectoryContent::NoContent,
+            metadata: EntryMetadata::default(),
+            source: None,
         }
     }
     pub fn path(&self) -> &str {
@@ -303,6 +1001,14 @@ impl DirectoryEntry {
     pub fn name(&self) -> &str {
         &self.name
     }
+    /// The long file name if there is one, otherwise the 8.3 name including the extension
+    pub fn full_name(&self) -> &str {
+        &self.full_name
+    }
+    /// The 8.3 name including the extension, even if there is a long file name
+    pub fn short_name(&self) -> &str {
+        &self.short_name
+    }
     pub fn file(&self) -> Option<&Vec<u8>> {
         match &self.content {
             DirectoryContent::File(f) => Some(f),
@@ -318,238 +1024,3185 @@ impl DirectoryEntry {
     pub fn content(&self) -> &DirectoryContent {
         &self.content
     }
+    pub fn metadata(&self) -> &EntryMetadata {
+        &self.metadata
+    }
+    pub fn size(&self) -> u32 {
+        self.metadata.size
+    }
+    pub fn attributes(&self) -> u8 {
+        self.metadata.attributes
+    }
+    pub fn flags(&self) -> DirectoryFlags {
+        self.metadata.flags()
+    }
+    pub fn is_readonly(&self) -> bool {
+        self.metadata.is_readonly()
+    }
+    pub fn is_hidden(&self) -> bool {
+        self.metadata.is_hidden()
+    }
+    pub fn is_system(&self) -> bool {
+        self.metadata.is_system()
+    }
+    pub fn is_volume_label(&self) -> bool {
+        self.metadata.is_volume_label()
+    }
+    pub fn is_archive(&self) -> bool {
+        self.metadata.is_archive()
+    }
+    pub fn is_directory(&self) -> bool {
+        self.metadata.attributes & DirectoryFlags::A_DIR != 0
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.metadata.deleted
+    }
+    /// Whether this is a subdirectory's `.` entry, only returned when asked for
+    pub fn is_dot(&self) -> bool {
+        self.is_directory() && self.name == "."
+    }
+    /// Whether this is a subdirectory's `..` entry, only returned when asked for
+    pub fn is_dotdot(&self) -> bool {
+        self.is_directory() && self.name == ".."
+    }
+    /// The first cluster of the entry's chain, if it was read from a VFF
+    pub fn start_cluster(&self) -> Option<u32> {
+        self.source.as_ref().map(|source| source.start)
+    }
+    /// The SHA-256 of a file entry, streamed through `reader` so the file is never held in memory
+    #[cfg(feature = "sha2")]
+    pub fn sha256(&self) -> Result<[u8; 32]> {
+        use sha2::{Digest, Sha256};
+        let mut reader = self.reader()?;
+        let mut hasher = Sha256::new();
+        let mut buf = [0u8; 0x1000];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => break,
+                len => hasher.update(&buf[..len]),
+            }
+        }
+        Ok(hasher.finalize().into())
+    }
+    /// Get the bytes of a file entry, reading them from the VFF if they haven't been loaded yet
+    pub fn load_bytes(&self) -> Result<Vec<u8>> {
+        match (&self.content, &self.source) {
+            (DirectoryContent::File(f), _) => Ok(f.clone()),
+            (DirectoryContent::Unloaded, Some(source)) => source
+                .vff
+                .borrow_mut()
+                .read_file(source.start, self.metadata.size),
+            _ => Err(VFFError::Other(format!("{} is not a file", self.name))),
+        }
+    }
+    /// Stream a file entry's contents without reading them all up front
+    pub fn reader(&self) -> Result<FileReader> {
+        match (&self.content, &self.source) {
+            (DirectoryContent::File(_) | DirectoryContent::Unloaded, Some(source)) => Ok(
+                FileReader::new(source.vff.clone(), source.start, self.metadata.size),
+            ),
+            _ => Err(VFFError::Other(format!(
+                "{} is not a file stored in a VFF",
+                self.name
+            ))),
+        }
+    }
+    fn with_metadata(mut self, metadata: EntryMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+    fn with_source(mut self, vff: Rc<RefCell<VFF>>, start: u32) -> Self {
+        self.source = Some(EntrySource { vff, start });
+        self
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Directory {
+/// Reads a file from the VFF one cluster at a time, following the chain as it goes
+#[derive(Debug)]
+pub struct FileReader {
     vff: Rc<RefCell<VFF>>,
-    data: Vec<u8>,
-    path: String,
+    /// The first cluster and size of the file, for warnings
+    start: u32,
+    size: u32,
+    clusters_read: u32,
+    /// The next cluster to load, None once the end of the chain is reached
+    next_cluster: Option<u32>,
+    buf: Vec<u8>,
+    buf_pos: usize,
+    /// Bytes of the file that haven't been returned yet
+    remaining: u64,
+    /// The current offset into the file, which a seek may put past the end
+    pos: u64,
 }
 
-impl Directory {
-    pub fn new(vff: Rc<RefCell<VFF>>, data: Vec<u8>, path: String) -> Result<Self> {
-        let data_len = data.len();
-        if data_len % 32 != 0 {
+impl FileReader {
+    pub fn new(vff: Rc<RefCell<VFF>>, start: u32, size: u32) -> Self {
+        FileReader {
+            vff,
+            start,
+            size,
+            clusters_read: 0,
+            next_cluster: if size == 0 { None } else { Some(start) },
+            buf: Vec::new(),
+            buf_pos: 0,
+            remaining: size as u64,
+            pos: 0,
+        }
+    }
+
+    fn fill(&mut self) -> Result<()> {
+        let Some(cluster) = self.next_cluster else {
+            let mut vff = self.vff.borrow_mut();
+            if vff.lenient {
+                vff.warnings.push(Warning::SizeMismatch {
+                    start: self.start,
+                    size: self.size,
+                    clusters: self.clusters_read,
+                });
+                self.remaining = 0;
+                return Ok(());
+            }
+            return Err(VFFError::InvalidData {
+                context: "FileReader reading the next cluster".to_owned(),
+                expected: format!("{} more bytes in the chain", self.remaining),
+                found: "The end of the chain".to_owned(),
+                offset: None,
+            });
+        };
+        let mut vff = self.vff.borrow_mut();
+        let fat = vff.fat()?;
+        if !fat.is_used(cluster) {
             return Err(VFFError::InvalidData {
-                context: "Directory::new".to_owned(),
-                expected: "Construct directory with a multiple of 32 bytes".to_owned(),
-                found: format!("Constructed with {data_len} (not multiple of 32"),
+                context: "FileReader reading the next cluster".to_owned(),
+                expected: "A cluster that satisfies is_used".to_owned(),
+                found: format!("{cluster:04x}"),
+                offset: None,
             });
         }
-        Ok(Directory { vff, data, path })
-    }
-    fn read(&self, show_deleted: bool) -> Result<Vec<ParsedFATEntry>> {
-        let mut files: Vec<ParsedFATEntry> = Vec::new();
-        for chunk in self.data.chunks_exact(32) {
-            let mut chunk = <[u8; 32]>::try_from(chunk).unwrap(); // Won't panic because we got our slice from chunks_exact
-            let mut parsed_entry = ParsedFATEntry::from_slice(&mut chunk)?;
-            match parsed_entry.name[0] {
-                0x0 => {
-                    continue;
-                } //free entry marker
-                0xe5 => {
-                    //deleted entry marker
-                    if !show_deleted {
-                        continue;
-                    }
-                    parsed_entry.deleted = true;
-                }
-                _ => {}
+        let start = self.start;
+        let next = match fat.get_cluster(cluster) {
+            Ok(next) => next,
+            Err(_) if vff.lenient => {
+                vff.warnings.push(Warning::OutOfRange { start, cluster });
+                self.remaining = 0;
+                return Ok(());
             }
-            if parsed_entry.attr & 0xf == 0xf {
-                continue;
+            Err(e) => return Err(e),
+        };
+        self.next_cluster = if fat.is_used(next) {
+            Some(next)
+        } else if fat.is_last(next) {
+            None
+        } else if vff.lenient && fat.is_bad(next) {
+            vff.warnings.push(Warning::BadCluster { start, cluster });
+            self.remaining = 0;
+            return Ok(());
+        } else if vff.lenient {
+            // Keep this cluster, but the file can't go past it
+            vff.warnings.push(Warning::InvalidTerminator {
+                start,
+                cluster,
+                value: next,
+            });
+            self.remaining = self.remaining.min(vff.header.cluster_size as u64);
+            None
+        } else {
+            return Err(VFFError::InvalidData {
+                context: "FileReader following the chain".to_owned(),
+                expected: "The first unused cluster in the chain should satisfy is_last".to_owned(),
+                found: format!("False, the cluster reads: {next:04x}"),
+                offset: fat.entry_offset(cluster),
+            });
+        };
+        self.buf = vff.read_cluster(cluster)?;
+        self.buf_pos = 0;
+        self.clusters_read += 1;
+        Ok(())
+    }
+}
+
+impl Read for FileReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || out.is_empty() {
+            return Ok(0);
+        }
+        if self.buf_pos == self.buf.len() {
+            self.fill()?;
+            // A lenient read may have ended the file early
+            if self.remaining == 0 {
+                return Ok(0);
             }
-            files.push(parsed_entry);
         }
-        Ok(files)
+        let available = &self.buf[self.buf_pos..];
+        let len = out
+            .len()
+            .min(available.len())
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        out[..len].copy_from_slice(&available[..len]);
+        self.buf_pos += len;
+        self.remaining -= len as u64;
+        self.pos += len as u64;
+        Ok(len)
     }
+}
 
-    fn get(&self, name: String, show_deleted: bool) -> Result<DirectoryEntry> {
-        for entry in self.read(show_deleted)? {
-            let entry_name = entry.nice_name();
-            if entry_name.to_ascii_lowercase() == name.to_ascii_lowercase() {
-                // Match!
-                if entry.attr & DirectoryFlags::A_DIR != 0 {
-                    // It's a directory
-                    let new_data = self.vff.borrow_mut().read_chain(entry.start.into())?;
-                    let path = self.path.clone() + "/" + &entry_name;
-                    return Ok(DirectoryEntry::make_dir_entry(
-                        self.path.clone(),
-                        entry_name,
-                        Directory::new(self.vff.clone(), new_data, path)?,
-                    ));
-                } else if entry.size == 0 {
-                    // It's an empty file
-                    return Ok(DirectoryEntry::make_empty_file_entry(
-                        self.path.clone(),
-                        entry_name,
-                    ));
-                } else {
-                    let mut vff = self.vff.borrow_mut();
-                    let mut raw = vff.read_chain(entry.start.into())?;
-                    raw.truncate(entry.size as usize);
-                    drop(vff);
-
-                    return Ok(DirectoryEntry::make_file_entry(
-                        self.path.clone(),
-                        entry_name,
-                        raw,
-                    ));
+impl Seek for FileReader {
+    /// Only the FAT links up to the target are followed, the data of the clusters before it
+    /// isn't read.
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(delta) => (self.size as u64).checked_add_signed(delta),
+            io::SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        let Some(target) = target else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative offset",
+            ));
+        };
+        self.buf.clear();
+        self.buf_pos = 0;
+        self.pos = target;
+        self.remaining = (self.size as u64).saturating_sub(target);
+        if self.remaining == 0 {
+            self.next_cluster = None;
+            return Ok(target);
+        }
+        let cluster_size = self.vff.borrow().header.cluster_size as u64;
+        let index = target / cluster_size;
+        let mut cluster = self.start;
+        {
+            let vff = self.vff.borrow();
+            let fat = vff.fat()?;
+            for _ in 0..index {
+                let next = fat.get_cluster(cluster)?;
+                if !fat.is_used(next) {
+                    return Err(VFFError::InvalidData {
+                        context: "FileReader seeking through the chain".to_owned(),
+                        expected: format!("At least {} clusters in the chain", index + 1),
+                        found: format!("The chain ends at {cluster:04x} with {next:04x}"),
+                        offset: fat.entry_offset(cluster),
+                    }
+                    .into());
                 }
+                cluster = next;
             }
         }
-        Ok(DirectoryEntry::make_no_content(self.path.clone()))
+        self.next_cluster = Some(cluster);
+        self.clusters_read = index as u32;
+        self.fill()?;
+        self.buf_pos = ((target % cluster_size) as usize).min(self.buf.len());
+        if self.next_cluster.is_none() {
+            // A lenient fill can cap the file at this cluster
+            self.remaining = self.remaining.min((self.buf.len() - self.buf_pos) as u64);
+        }
+        Ok(target)
     }
+}
 
-    pub fn ls(&self, include_deleted: bool) -> Result<Vec<String>> {
-        self.do_operation_recursive(None, include_deleted)
-    }
+/// What `Directory::dump_with_progress` is up to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// A file that will be written, `size` bytes long. Every file is discovered before the
+    /// first one is written, so these can be used to work out the total.
+    Discovered { path: String, size: u32 },
+    /// A file was written
+    Written { path: String, bytes: u64 },
+}
 
-    pub fn dump(&self, dump_location: PathBuf, include_deleted: bool) -> Result<()> {
-        std::fs::create_dir_all(&dump_location)?;
-        self.do_operation_recursive(Some(dump_location), include_deleted)?;
-        Ok(())
+/// Hands out the paths a dump writes to, so that entries whose names only differ in case don't
+/// overwrite each other on case-insensitive filesystems
+#[derive(Default)]
+struct DumpPaths {
+    /// Where each directory was written, keyed by where it would have gone
+    moved: HashMap<PathBuf, PathBuf>,
+    /// Every path handed out so far, lowercased
+    taken: HashSet<String>,
+}
+
+impl DumpPaths {
+    /// Where to write the entry meant for `out`. Below a renamed directory, or if the name is
+    /// taken, this differs from `out`. A taken name gets the first free ` (2)`, ` (3)`, ...
+    /// suffix before its extension.
+    fn claim(&mut self, out: PathBuf, is_dir: bool) -> PathBuf {
+        let (Some(parent), Some(name)) = (out.parent(), out.file_name()) else {
+            return out;
+        };
+        let parent = self
+            .moved
+            .get(parent)
+            .cloned()
+            .unwrap_or(parent.to_path_buf());
+        let name = name.to_string_lossy();
+        let (stem, ext) = match name.rfind('.') {
+            Some(dot) if dot > 0 => name.split_at(dot),
+            _ => (&*name, ""),
+        };
+        let mut actual = parent.join(&*name);
+        let mut n = 2;
+        while !self.taken.insert(actual.to_string_lossy().to_lowercase()) {
+            actual = parent.join(format!("{stem} ({n}){ext}"));
+            n += 1;
+        }
+        if is_dir {
+            self.moved.insert(out, actual.clone());
+        }
+        actual
     }
+}
 
-    fn do_operation_recursive(
+/// `name` with anything that would take it outside the directory it's written to replaced by
+/// `_`, or `None` if it can be used as it is. `.`, `..` and empty names become underscores.
+fn escape_file_name(name: &str) -> Option<String> {
+    let escaped = match name {
+        "" | "." | ".." => "_".repeat(name.len().max(1)),
+        _ => name.replace(['/', '\\', '\0'], "_"),
+    };
+    (escaped != name).then_some(escaped)
+}
+
+/// How a dump lays out the files it writes
+#[derive(Clone, Copy)]
+struct DumpLayout {
+    /// Which names the files are written under
+    style: NameStyle,
+    /// Whether every file goes straight into the dump directory, see `Directory::dump_flat`
+    flatten: bool,
+}
+
+/// The outcome of `Directory::dump_named_skipping_errors`
+#[derive(Debug)]
+pub struct DumpReport {
+    /// What `Directory::dump_named` would have returned
+    pub aliases: Vec<(String, String)>,
+    /// The relative path of each file that couldn't be read, and why
+    pub failed: Vec<(String, VFFError)>,
+}
+
+/// Which of its names `Directory::dump_named` writes an entry under
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameStyle {
+    /// The long file name, or the 8.3 name for entries without one
+    #[default]
+    Long,
+    /// Always the 8.3 name
+    Short,
+    /// The long file name, also reporting the 8.3 alias
+    Both,
+}
+
+/// How `Directory::get_path_with` matches path components against entry names. The default
+/// ignores ASCII case and also accepts the 8.3 short name of entries with a long name.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LookupOptions {
+    pub case_sensitive: bool,
+    /// Only match the full name, the long name if there is one and the 8.3 name otherwise
+    pub exact: bool,
+}
+
+impl LookupOptions {
+    fn matches(&self, entry: &ParsedFATEntry, name: &str, encoding: NameEncoding) -> bool {
+        let eq = |candidate: String| match self.case_sensitive {
+            true => candidate == name,
+            false => candidate.eq_ignore_ascii_case(name),
+        };
+        if self.exact {
+            return eq(entry.full_name_in(encoding));
+        }
+        // short_name_in always appends the dot, so names without an extension need the bare name
+        eq(entry.full_name_in(encoding))
+            || eq(entry.short_name_in(encoding))
+            || (entry.extension_in(encoding).is_empty() && eq(entry.name_in(encoding)))
+    }
+}
+
+/// A pre-order walk of a directory tree that keeps its own stack instead of recursing, see
+/// `Directory::walk`
+#[derive(Debug)]
+pub struct WalkIter {
+    include_deleted: bool,
+    /// The remaining entries of each directory being walked, innermost last
+    stack: Vec<std::vec::IntoIter<DirectoryEntry>>,
+    /// The directory just returned, whose entries come next
+    pending: Option<Directory>,
+}
+
+impl Iterator for WalkIter {
+    type Item = Result<DirectoryEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(dir) = self.pending.take() {
+            match dir.entries(self.include_deleted, false) {
+                Ok(entries) => self.stack.push(entries.into_iter()),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        while let Some(entries) = self.stack.last_mut() {
+            match entries.next() {
+                Some(entry) => {
+                    self.pending = entry.dir().cloned();
+                    return Some(Ok(entry));
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+        None
+    }
+}
+
+/// One node of the tree returned by `Directory::listing`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ListingNode {
+    pub name: String,
+    pub path: String,
+    pub size: u32,
+    pub attributes: u8,
+    pub deleted: bool,
+    pub children: Vec<ListingNode>,
+}
+
+/// A line of `Directory::ls`, before the size and deleted marker are added to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsEntry {
+    /// Always starts with `/`
+    pub path: String,
+    /// The file size, `None` for an empty directory
+    pub size: Option<u32>,
+    pub deleted: bool,
+    /// The first cluster of the entry's chain, if it was read from a VFF. Empty files have no
+    /// chain and start at 0.
+    pub start: Option<u32>,
+}
+
+impl LsEntry {
+    fn decorated(&self) -> String {
+        let mut line = self.path.clone();
+        if let Some(size) = self.size {
+            line += &format!(" [{size:#06x}]");
+        }
+        if self.deleted {
+            line += " [DELETED]";
+        }
+        line
+    }
+}
+
+/// Everything the directory entry records about a file or directory, see
+/// `Directory::list_detailed`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// Always starts with `/`
+    pub path: String,
+    pub size: u32,
+    pub is_dir: bool,
+    pub deleted: bool,
+    pub attributes: DirectoryFlags,
+    pub created: Option<FatDateTime>,
+    pub modified: Option<FatDateTime>,
+    /// FAT only records the date of last access
+    pub accessed: Option<FatDateTime>,
+    /// The first cluster of the entry's chain, 0 for an empty file
+    pub start: u32,
+}
+
+impl FileInfo {
+    /// Whether the archive attribute is set, meaning the file changed since it was last backed
+    /// up
+    pub fn is_archive(&self) -> bool {
+        self.attributes.contains(DirectoryFlags::A_A)
+    }
+}
+
+impl From<&DirectoryEntry> for FileInfo {
+    fn from(entry: &DirectoryEntry) -> Self {
+        let metadata = entry.metadata();
+        FileInfo {
+            path: entry.path().to_owned() + "/" + entry.full_name(),
+            size: metadata.size,
+            is_dir: entry.is_directory(),
+            deleted: metadata.deleted,
+            attributes: metadata.flags(),
+            created: metadata.created,
+            modified: metadata.modified,
+            accessed: metadata.accessed,
+            start: entry.start_cluster().unwrap_or(0),
+        }
+    }
+}
+
+/// A path that differs between two trees, see `Directory::diff`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffEntry {
+    /// Only in the other tree
+    Added(String),
+    /// Only in this tree
+    Removed(String),
+    /// A file in both trees with different contents, or a file in one and a directory in the
+    /// other
+    Modified(String),
+}
+
+impl DiffEntry {
+    /// The path, relative to the directories that were compared
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Added(path) | Self::Removed(path) | Self::Modified(path) => path,
+        }
+    }
+}
+
+/// A directory read from a VFF.
+///
+/// Directories and their entries share the VFF they came from as an `Rc<RefCell<VFF>>`, so
+/// they can't be sent to another thread, see `VffOptions::open_owned` for that. Reading a
+/// file or a subdirectory borrows the VFF mutably for just that read. That means:
+///
+/// - Holding a borrow of the VFF, such as the `Ref` from `vff.borrow()`, while reading through
+///   a `Directory`, `DirectoryEntry` or `FileReader` panics with "already borrowed". Copy out
+///   what's needed from the VFF and drop the borrow first.
+/// - No borrow is held while calling back into user code, like the `visit` visitor or the
+///   `dump_with_progress` callback, so those are free to read other files or borrow the VFF.
+/// - Several `FileReader`s can be open at once.
+#[derive(Debug, Clone)]
+pub struct Directory {
+    vff: Rc<RefCell<VFF>>,
+    data: Vec<u8>,
+    path: String,
+    /// The start clusters of this directory and the ones leading to it, with 0 for the root.
+    /// Empty if this directory wasn't reached from the root.
+    ancestors: Vec<u32>,
+}
+
+impl Directory {
+    /// `data` must be a whole number of 32 byte entries. In lenient mode, a partial entry at
+    /// the end is dropped with a warning instead.
+    pub fn new(vff: Rc<RefCell<VFF>>, mut data: Vec<u8>, path: String) -> Result<Self> {
+        let data_len = data.len();
+        if !data_len.is_multiple_of(32) {
+            if !vff.borrow().lenient {
+                return Err(VFFError::InvalidData {
+                    context: "Directory::new".to_owned(),
+                    expected: "Construct directory with a multiple of 32 bytes".to_owned(),
+                    found: format!("Constructed with {data_len} (not multiple of 32"),
+                    offset: None,
+                });
+            }
+            vff.borrow_mut().warnings.push(Warning::UnalignedDirectory {
+                path: path.clone(),
+                len: data_len,
+            });
+            data.truncate(data_len - data_len % 32);
+        }
+        Ok(Directory {
+            vff,
+            data,
+            path,
+            ancestors: Vec::new(),
+        })
+    }
+
+    fn root(vff: Rc<RefCell<VFF>>, data: Vec<u8>) -> Result<Self> {
+        let mut root = Directory::new(vff, data, String::with_capacity(0))?;
+        root.ancestors.push(0);
+        Ok(root)
+    }
+    fn read(&self, show_deleted: bool) -> Result<Vec<ParsedFATEntry>> {
+        parse_directory(&self.data, show_deleted)
+    }
+
+    /// The entries of this directory as they're stored, including `.` and `..`. Long name
+    /// slots are folded into `long_name` of the entry they belong to.
+    pub fn raw_entries(&self, include_deleted: bool) -> Result<Vec<ParsedFATEntry>> {
+        self.read(include_deleted)
+    }
+
+    fn get_matching(
         &self,
-        dump: Option<PathBuf>,
         show_deleted: bool,
-    ) -> Result<Vec<String>> {
-        let mut res: Vec<String> = Vec::new();
-        // We need to make sure our directory gets added if it's empty
-        // we can't just check the length of the result from read(), because we might filter out a result like '.' or '..'
-        let mut got_ourself = false;
+        matches: impl Fn(&ParsedFATEntry) -> bool,
+    ) -> Result<DirectoryEntry> {
         for entry in self.read(show_deleted)? {
+            if matches(&entry) {
+                return self.load_entry(entry);
+            }
+        }
+        Ok(DirectoryEntry::make_no_content(self.path.clone()))
+    }
+
+    fn load_entry(&self, entry: ParsedFATEntry) -> Result<DirectoryEntry> {
+        let encoding = self.vff.borrow().name_encoding;
+        let entry_name = entry.name_in(encoding);
+        let full_name = entry.full_name_in(encoding);
+        let metadata = EntryMetadata::from(&entry);
+        let start = entry.start.into();
+        let mut ret = if entry.attr & DirectoryFlags::A_DIR != 0 {
+            // It's a directory
+            let path = self.path.clone() + "/" + &full_name;
+            let mut ancestors = self.ancestors.clone();
+            let new_data = if entry.is_dotdot() {
+                ancestors.pop();
+                self.vff.borrow_mut().read_entry_chain(start)?
+            } else if entry.is_dot() {
+                self.vff.borrow_mut().read_entry_chain(start)?
+            } else if ancestors.contains(&start) {
+                // Entering it would never end, so it's left empty
+                let warning = Warning::DirectoryCycle {
+                    path: path.clone(),
+                    start,
+                };
+                self.vff.borrow_mut().warnings.push(warning);
+                Vec::new()
+            } else {
+                ancestors.push(start);
+                self.vff.borrow_mut().read_entry_chain(start)?
+            };
+            let mut dir = Directory::new(self.vff.clone(), new_data, path)?;
+            dir.ancestors = ancestors;
+            DirectoryEntry::make_dir_entry(self.path.clone(), entry_name, dir)
+        } else {
+            let raw = self.vff.borrow_mut().read_file(start, entry.size)?;
+            DirectoryEntry::make_file_entry(self.path.clone(), entry_name, raw)
+        };
+        ret.full_name = full_name;
+        ret.short_name = entry.short_name_in(encoding);
+        Ok(ret
+            .with_metadata(metadata)
+            .with_source(self.vff.clone(), start))
+    }
+
+    /// List the entries of this directory without recursing into subdirectories.
+    ///
+    /// Subdirectories are read so they can be browsed further, but file contents are left
+    /// `Unloaded` until `DirectoryEntry::load_bytes` is called.
+    pub fn entries(
+        &self,
+        include_deleted: bool,
+        include_dots: bool,
+    ) -> Result<Vec<DirectoryEntry>> {
+        let mut ret = Vec::new();
+        let encoding = self.vff.borrow().name_encoding;
+        for entry in self.read(include_deleted)? {
+            if !include_dots && entry.is_dot_or_dotdot() {
+                continue;
+            }
             if entry.attr & DirectoryFlags::A_DIR != 0 {
-                match entry.nice_name().as_ref() {
-                    "." | ".." => continue,
-                    _ => {
-                        got_ourself = true;
-                    }
+                ret.push(self.load_entry(entry)?);
+                continue;
+            }
+            ret.push(DirectoryEntry {
+                path: self.path.clone(),
+                name: entry.name_in(encoding),
+                full_name: entry.full_name_in(encoding),
+                short_name: entry.short_name_in(encoding),
+                content: DirectoryContent::Unloaded,
+                metadata: EntryMetadata::from(&entry),
+                source: Some(EntrySource {
+                    vff: self.vff.clone(),
+                    start: entry.start.into(),
+                }),
+            });
+        }
+        Ok(ret)
+    }
+
+    /// Build a tree describing everything under this directory without reading file contents
+    pub fn listing(&self, include_deleted: bool) -> Result<Vec<ListingNode>> {
+        let mut ret = Vec::new();
+        for entry in self.entries(include_deleted, false)? {
+            let children = match entry.dir() {
+                Some(dir) => dir.listing(include_deleted)?,
+                None => Vec::new(),
+            };
+            ret.push(ListingNode {
+                path: entry.path().to_owned() + "/" + entry.full_name(),
+                name: entry.full_name,
+                size: entry.metadata.size,
+                attributes: entry.metadata.attributes,
+                deleted: entry.metadata.deleted,
+                children,
+            });
+        }
+        Ok(ret)
+    }
+
+    /// Look up an entry by a `/` separated path relative to this directory.
+    ///
+    /// Each component is matched case-insensitively against the full 8.3 name.
+    /// Returns a `NoContent` entry if any component doesn't exist.
+    pub fn get_path(&self, path: &str) -> Result<DirectoryEntry> {
+        self.get_path_with(path, LookupOptions::default())
+    }
+
+    /// Like `get_path`, but with control over how each component is matched
+    pub fn get_path_with(&self, path: &str, options: LookupOptions) -> Result<DirectoryEntry> {
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let Some((last, parents)) = components.split_last() else {
+            // An empty path refers to this directory
+            let metadata = EntryMetadata {
+                attributes: DirectoryFlags::A_DIR.bits(),
+                ..Default::default()
+            };
+            return Ok(DirectoryEntry::make_dir_entry(
+                self.path.clone(),
+                String::new(),
+                self.clone(),
+            )
+            .with_metadata(metadata));
+        };
+        let mut current: Option<Directory> = None;
+        for component in parents {
+            let dir = current.as_ref().unwrap_or(self);
+            current = match dir.get_full_name(component, options)?.content {
+                DirectoryContent::Dir(next) => Some(next),
+                DirectoryContent::File(_) | DirectoryContent::Unloaded => {
+                    return Err(VFFError::InvalidData {
+                        context: format!("Directory::get_path resolving {path}"),
+                        expected: format!("{component} to be a directory"),
+                        found: "a file".to_owned(),
+                        offset: None,
+                    });
                 }
-                let maybe_error = "Directory::get should return another Directory because the entry is marked as one in the FAT".to_owned();
-                #[allow(unused_assignments)]
-                let mut maybe_found = "Placeholder error text";
-                match self.get(entry.nice_name(), show_deleted)?.content {
-                    DirectoryContent::Dir(dir) => {
-                        let new_dump = match &dump {
-                            Some(path) => {
-                                let mut temp = path.to_owned();
-                                temp.push(&entry.nice_name());
-                                std::fs::create_dir_all(path)?;
-                                Some(temp)
-                            }
-                            None => None,
-                        };
-                        let directory_recused =
-                            dir.do_operation_recursive(new_dump, show_deleted)?;
-                        res.extend(directory_recused);
-                        continue;
-                    }
-                    DirectoryContent::File(_) => {
-                        maybe_found = "returned file contents";
-                    }
-                    DirectoryContent::NoContent => {
-                        maybe_found = "returned nothing";
+                DirectoryContent::NoContent => {
+                    return Ok(DirectoryEntry::make_no_content(dir.path.clone()));
+                }
+            };
+        }
+        current
+            .as_ref()
+            .unwrap_or(self)
+            .get_full_name(last, options)
+    }
+
+    /// The contents of the file called `name` in this directory, matched like a `get_path`
+    /// component. Fails if there's no such file or it's a directory.
+    pub fn read_file(&self, name: &str) -> Result<Vec<u8>> {
+        let entry = self.get_full_name(name, LookupOptions::default())?;
+        match entry.content() {
+            DirectoryContent::Dir(_) => Err(VFFError::Other(format!("{name} is a directory"))),
+            DirectoryContent::NoContent => Err(VFFError::Other(format!("{name} not found"))),
+            _ => entry.load_bytes(),
+        }
+    }
+
+    fn get_full_name(&self, name: &str, options: LookupOptions) -> Result<DirectoryEntry> {
+        let encoding = self.vff.borrow().name_encoding;
+        self.get_matching(false, |entry| options.matches(entry, name, encoding))
+    }
+
+    /// Walk everything under this directory, calling `visitor` for each file and directory.
+    ///
+    /// Directories are visited before their contents and `.`/`..` are skipped. File contents
+    /// are left `Unloaded`, so the visitor only pays for reading the files it cares about.
+    pub fn visit(
+        &self,
+        include_deleted: bool,
+        visitor: &mut dyn FnMut(&DirectoryEntry) -> Result<()>,
+    ) -> Result<()> {
+        for entry in self.walk(include_deleted) {
+            visitor(&entry?)?;
+        }
+        Ok(())
+    }
+
+    /// Iterate over everything under this directory, in the same order as `visit`.
+    ///
+    /// Each directory is only read once the walk reaches it, so stopping early skips the rest.
+    pub fn walk(&self, include_deleted: bool) -> WalkIter {
+        WalkIter {
+            include_deleted,
+            stack: Vec::new(),
+            pending: Some(self.clone()),
+        }
+    }
+
+    /// Whether this directory has anything besides `.` and `..`
+    fn is_empty(&self, include_deleted: bool) -> Result<bool> {
+        Ok(self
+            .read(include_deleted)?
+            .iter()
+            .all(ParsedFATEntry::is_dot_or_dotdot))
+    }
+
+    /// Every file under this directory, plus any empty directories, as `/path [size]` lines.
+    /// Deleted files are marked with ` [DELETED]`. An empty root directory has no lines at all.
+    /// Use `ls_entries` to get the paths without the decorations.
+    pub fn ls(&self, include_deleted: bool) -> Result<Vec<String>> {
+        Ok(self
+            .ls_entries(include_deleted)?
+            .iter()
+            .map(LsEntry::decorated)
+            .collect())
+    }
+
+    /// Like `ls`, but only lists the entries `filter` accepts
+    pub fn ls_matching(
+        &self,
+        include_deleted: bool,
+        filter: &dyn Fn(&DirectoryEntry) -> bool,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .ls_entries_matching(include_deleted, filter)?
+            .iter()
+            .map(LsEntry::decorated)
+            .collect())
+    }
+
+    /// The entries `ls` lists, undecorated
+    pub fn ls_entries(&self, include_deleted: bool) -> Result<Vec<LsEntry>> {
+        let mut res = Vec::new();
+        // Empty directories still get a line so they show up in the listing, but there's
+        // nothing to show for an empty root
+        if !self.path.is_empty() && self.is_empty(include_deleted)? {
+            res.push(LsEntry {
+                path: self.path.clone(),
+                size: None,
+                deleted: false,
+                start: self.ancestors.last().copied(),
+            });
+        }
+        res.extend(self.ls_entries_matching(include_deleted, &|_| true)?);
+        Ok(res)
+    }
+
+    /// Like `ls_entries`, but only lists the entries `filter` accepts
+    pub fn ls_entries_matching(
+        &self,
+        include_deleted: bool,
+        filter: &dyn Fn(&DirectoryEntry) -> bool,
+    ) -> Result<Vec<LsEntry>> {
+        let mut res = Vec::new();
+        self.visit(include_deleted, &mut |entry| {
+            if !filter(entry) {
+                return Ok(());
+            }
+            match entry.dir() {
+                Some(dir) => {
+                    if dir.is_empty(include_deleted)? {
+                        res.push(LsEntry {
+                            path: dir.path.clone(),
+                            size: None,
+                            deleted: entry.is_deleted(),
+                            start: entry.start_cluster(),
+                        });
                     }
                 }
-                return Err(VFFError::InvalidData {
-                    context: "Directory::ls get entry from read".to_owned(),
-                    expected: maybe_error,
-                    found: maybe_found.to_owned(),
+                None => res.push(LsEntry {
+                    path: entry.path().to_owned() + "/" + entry.full_name(),
+                    size: Some(entry.size()),
+                    deleted: entry.is_deleted(),
+                    start: entry.start_cluster(),
+                }),
+            }
+            Ok(())
+        })?;
+        Ok(res)
+    }
+
+    /// The path of every file under this directory, in the same order as `visit`. Directories
+    /// aren't listed on their own, and file contents aren't read.
+    pub fn paths(&self, include_deleted: bool) -> Result<Vec<String>> {
+        let mut res = Vec::new();
+        self.visit(include_deleted, &mut |entry| {
+            if !entry.is_directory() {
+                res.push(entry.path().to_owned() + "/" + entry.full_name());
+            }
+            Ok(())
+        })?;
+        Ok(res)
+    }
+
+    /// Every file and directory under this directory, in the same order as `visit`, with the
+    /// metadata from its entry. File contents aren't read.
+    pub fn list_detailed(&self, include_deleted: bool) -> Result<Vec<FileInfo>> {
+        let mut res = Vec::new();
+        self.visit(include_deleted, &mut |entry| {
+            res.push(FileInfo::from(entry));
+            Ok(())
+        })?;
+        Ok(res)
+    }
+
+    /// Render everything under this directory as an indented tree, one line per entry.
+    ///
+    /// Only entries `filter` accepts are shown, along with the directories leading to them.
+    pub fn tree(
+        &self,
+        include_deleted: bool,
+        filter: &dyn Fn(&DirectoryEntry) -> bool,
+    ) -> Result<Vec<String>> {
+        let base_depth = self.path.matches('/').count();
+        let mut entries = Vec::new();
+        self.visit(include_deleted, &mut |entry| {
+            let depth = entry.path().matches('/').count() - base_depth;
+            entries.push((depth, filter(entry), entry.clone()));
+            Ok(())
+        })?;
+        // Walk backwards so every directory has seen its contents before deciding whether to
+        // keep it, and whether each entry is the last of its siblings
+        let max_depth = entries.iter().map(|(depth, ..)| *depth).max().unwrap_or(0);
+        let mut kept_below = vec![false; max_depth + 2];
+        let mut sibling_below = vec![false; max_depth + 2];
+        let mut shown = Vec::new();
+        for (depth, matched, entry) in entries.into_iter().rev() {
+            let keep =
+                matched || (entry.is_directory() && kept_below[depth + 1..].iter().any(|&k| k));
+            kept_below[depth + 1..].fill(false);
+            if !keep {
+                continue;
+            }
+            kept_below[depth] = true;
+            let last = !sibling_below[depth];
+            sibling_below[depth + 1..].fill(false);
+            sibling_below[depth] = true;
+            shown.push((depth, last, entry));
+        }
+        let mut res = vec![if self.path.is_empty() {
+            "/".to_owned()
+        } else {
+            self.path.clone()
+        }];
+        // Whether the ancestor at each depth was the last of its siblings
+        let mut ancestors_last: Vec<bool> = Vec::new();
+        for (depth, last, entry) in shown.into_iter().rev() {
+            ancestors_last.truncate(depth);
+            let mut line: String = ancestors_last
+                .iter()
+                .map(|&l| if l { "    " } else { "│   " })
+                .collect();
+            line += if last { "└── " } else { "├── " };
+            line += entry.full_name();
+            if entry.is_directory() {
+                line += "/";
+            } else {
+                line += &format!(" [{:#06x}]", entry.size());
+            }
+            if entry.is_deleted() {
+                line += " [DELETED]";
+            }
+            res.push(line);
+            ancestors_last.push(last);
+        }
+        Ok(res)
+    }
+
+    /// Extract everything under this directory to `dump_location`. Names are compared ignoring
+    /// case, and an entry whose name was already used in its directory gets a ` (2)` style
+    /// suffix, with a `Warning::NameCollision` saying so.
+    pub fn dump(&self, dump_location: PathBuf, include_deleted: bool) -> Result<()> {
+        self.dump_matching(dump_location, include_deleted, &|_| true)
+    }
+
+    /// Like `dump`, but only extracts the entries `filter` accepts. The parent directories of
+    /// extracted files are created as needed.
+    pub fn dump_matching(
+        &self,
+        dump_location: PathBuf,
+        include_deleted: bool,
+        filter: &dyn Fn(&DirectoryEntry) -> bool,
+    ) -> Result<()> {
+        self.dump_named(dump_location, include_deleted, filter, NameStyle::Long)?;
+        Ok(())
+    }
+
+    /// Like `dump_matching`, writing each entry under the name `style` picks.
+    ///
+    /// With `NameStyle::Both`, returns the relative path and 8.3 alias of every entry whose
+    /// alias differs from its long name. Otherwise nothing is returned.
+    pub fn dump_named(
+        &self,
+        dump_location: PathBuf,
+        include_deleted: bool,
+        filter: &dyn Fn(&DirectoryEntry) -> bool,
+        style: NameStyle,
+    ) -> Result<Vec<(String, String)>> {
+        self.dump_inner(
+            dump_location,
+            include_deleted,
+            filter,
+            DumpLayout {
+                style,
+                flatten: false,
+            },
+            None,
+            None,
+        )
+    }
+
+    /// The combined size of every file under this directory, which is how much `dump` writes.
+    /// Only the directories are read.
+    pub fn total_size(&self, include_deleted: bool) -> Result<u64> {
+        let mut total = 0;
+        self.visit(include_deleted, &mut |entry| {
+            if !entry.is_directory() {
+                total += entry.size() as u64;
+            }
+            Ok(())
+        })?;
+        Ok(total)
+    }
+
+    /// Like `dump`, calling `progress` as files are found and written. The paths passed to it
+    /// are relative to this directory.
+    pub fn dump_with_progress(
+        &self,
+        dump_location: PathBuf,
+        include_deleted: bool,
+        progress: &mut dyn FnMut(ProgressEvent),
+    ) -> Result<()> {
+        self.visit(include_deleted, &mut |entry| {
+            if !entry.is_directory() {
+                progress(ProgressEvent::Discovered {
+                    path: self.relative_path(entry),
+                    size: entry.size(),
                 });
-            } else if let Some(path) = &dump {
-                got_ourself = true;
-                if let DirectoryContent::File(file_bytes) =
-                    self.get(entry.nice_name(), show_deleted)?.content()
-                {
-                    std::fs::create_dir_all(path)?;
-                    let mut temp = path.to_owned();
-                    temp.push(&entry.nice_full_name());
-                    let mut f = BufWriter::new(File::create(temp)?);
-                    f.write_all(file_bytes.as_slice())?;
-                } else {
-                    return Err(VFFError::InvalidData {
-                        context: "Directory::ls dumping file get".to_owned(),
-                        expected: "Directory::get returns file bytes".to_owned(),
-                        found: "None".to_owned(),
-                    });
-                }
+            }
+            Ok(())
+        })?;
+        self.dump_inner(
+            dump_location,
+            include_deleted,
+            &|_| true,
+            DumpLayout {
+                style: NameStyle::Long,
+                flatten: false,
+            },
+            None,
+            Some(progress),
+        )?;
+        Ok(())
+    }
+
+    /// Like `dump_named`, but a file that can't be read doesn't stop the dump. Each failed file
+    /// is listed in the returned report, and whatever was written of it before the error is
+    /// left in place. Errors reading a directory or writing
+    /// to `dump_location` still stop the dump.
+    pub fn dump_named_skipping_errors(
+        &self,
+        dump_location: PathBuf,
+        include_deleted: bool,
+        filter: &dyn Fn(&DirectoryEntry) -> bool,
+        style: NameStyle,
+    ) -> Result<DumpReport> {
+        let mut failed = Vec::new();
+        let aliases = self.dump_inner(
+            dump_location,
+            include_deleted,
+            filter,
+            DumpLayout {
+                style,
+                flatten: false,
+            },
+            Some(&mut failed),
+            None,
+        )?;
+        Ok(DumpReport { aliases, failed })
+    }
+
+    /// Like `dump_named`, but every file goes straight into `dump_location`, named after its
+    /// relative path with each `/` replaced by `_`. No directories are created. With
+    /// `skip_errors`, files that can't be read are listed in the report as with
+    /// `dump_named_skipping_errors`, otherwise the first one stops the dump.
+    pub fn dump_flat(
+        &self,
+        dump_location: PathBuf,
+        include_deleted: bool,
+        filter: &dyn Fn(&DirectoryEntry) -> bool,
+        style: NameStyle,
+        skip_errors: bool,
+    ) -> Result<DumpReport> {
+        let mut failed = Vec::new();
+        let aliases = self.dump_inner(
+            dump_location,
+            include_deleted,
+            filter,
+            DumpLayout {
+                style,
+                flatten: true,
+            },
+            skip_errors.then_some(&mut failed),
+            None,
+        )?;
+        Ok(DumpReport { aliases, failed })
+    }
+
+    fn dump_inner(
+        &self,
+        dump_location: PathBuf,
+        include_deleted: bool,
+        filter: &dyn Fn(&DirectoryEntry) -> bool,
+        layout: DumpLayout,
+        mut failed: Option<&mut Vec<(String, VFFError)>>,
+        mut progress: Option<&mut dyn FnMut(ProgressEvent)>,
+    ) -> Result<Vec<(String, String)>> {
+        let DumpLayout { style, flatten } = layout;
+        std::fs::create_dir_all(&dump_location)?;
+        let mut aliases = Vec::new();
+        let mut dirs = HashMap::new();
+        let mut paths = DumpPaths::default();
+        self.visit(include_deleted, &mut |entry| {
+            // Directories still need a path when they don't match, for their contents
+            let matched = filter(entry);
+            if !matched && !entry.is_directory() {
+                return Ok(());
+            }
+            let name = match style {
+                NameStyle::Short => entry.short_name(),
+                NameStyle::Long | NameStyle::Both => entry.full_name(),
+            };
+            let relative = self.dump_relative_path(&mut dirs, entry, name);
+            if flatten && entry.is_directory() {
+                return Ok(());
+            }
+            let out = if flatten {
+                dump_location.join(relative.replace('/', "_"))
+            } else {
+                let mut out = dump_location.clone();
+                out.extend(relative.split('/'));
+                out
+            };
+            let out = self.claim_dump_path(&mut paths, out, entry);
+            if !matched {
+                return Ok(());
+            }
+            if style == NameStyle::Both && entry.short_name() != entry.full_name() {
+                aliases.push((self.relative_path(entry), entry.short_name().to_owned()));
+            }
+            if entry.is_directory() {
+                std::fs::create_dir_all(out)?;
             } else {
-                got_ourself = true;
-                let mut final_name = self.path.clone()
-                    + "/"
-                    + &entry.nice_full_name()
-                    + &format!(" [{:#06x}]", entry.size);
-                if entry.deleted {
-                    final_name += " [DELETED]"
+                if let Some(parent) = out.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut f = BufWriter::new(File::create(out)?);
+                match (Self::dump_file(entry, &mut f), failed.as_mut()) {
+                    (Err(e), Some(failed)) => failed.push((self.relative_path(entry), e)),
+                    (result, _) => {
+                        let bytes = result?;
+                        if let Some(progress) = progress.as_mut() {
+                            progress(ProgressEvent::Written {
+                                path: self.relative_path(entry),
+                                bytes,
+                            });
+                        }
+                    }
                 }
-                res.push(final_name);
             }
+            Ok(())
+        })?;
+        Ok(aliases)
+    }
+
+    fn dump_file(entry: &DirectoryEntry, f: &mut BufWriter<File>) -> Result<u64> {
+        let bytes = io::copy(&mut entry.reader()?, f)?;
+        f.flush()?;
+        if let Some(modified) = entry.metadata().modified {
+            f.get_ref().set_modified(modified.to_system_time())?;
         }
-        if !got_ourself {
-            res.push(self.path.to_owned());
+        Ok(bytes)
+    }
+
+    /// Try to bring back the deleted files under this directory, writing them below `dest`.
+    ///
+    /// This is best effort. Deleting a file overwrites the first byte of its 8.3 name, so
+    /// recovered files without a long name start with `_` instead. It also usually frees the
+    /// chain, so only the first cluster is known for sure. The rest is assumed to follow it
+    /// contiguously, as far as those clusters are still free. Any of the clusters may have been
+    /// reused by a newer file since, in which case the recovered file contains that data
+    /// instead. Deleted directories aren't entered. Names that can't be used as file names
+    /// are escaped, with a `Warning::UnsafeName`.
+    pub fn recover_deleted(&self, dest: PathBuf) -> Result<()> {
+        let encoding = self.vff.borrow().name_encoding;
+        for entry in self.read(true)? {
+            let is_dir = entry.attr & DirectoryFlags::A_DIR != 0;
+            if entry.deleted && !is_dir {
+                let data = self
+                    .vff
+                    .borrow()
+                    .recover_chain(entry.start.into(), entry.size)?;
+                let name: String = match &entry.long_name {
+                    Some(long_name) => long_name.clone(),
+                    None => std::iter::once('_')
+                        .chain(entry.full_name_in(encoding).chars().skip(1))
+                        .collect(),
+                };
+                let name = self.safe_file_name(&name, || self.path.clone() + "/" + &name);
+                std::fs::create_dir_all(&dest)?;
+                std::fs::write(dest.join(name), data)?;
+            } else if is_dir && !entry.deleted && !entry.is_dot_or_dotdot() {
+                let entry = self.load_entry(entry)?;
+                if let Some(dir) = entry.dir() {
+                    let name = self.safe_file_name(entry.full_name(), || dir.path.clone());
+                    dir.recover_deleted(dest.join(name))?;
+                }
+            }
         }
-        Ok(res)
+        Ok(())
     }
-}
 
-trait ReadSeek: Read + Seek + std::fmt::Debug {}
-impl<T> ReadSeek for T where T: Read + Seek + std::fmt::Debug {}
+    /// Write a `sha256sum` compatible manifest of the files `filter` accepts, with paths
+    /// relative to this directory so it can be checked from inside a dump of it
+    #[cfg(feature = "sha2")]
+    pub fn write_sha256_manifest(
+        &self,
+        mut out: impl Write,
+        include_deleted: bool,
+        filter: &dyn Fn(&DirectoryEntry) -> bool,
+    ) -> Result<()> {
+        self.visit(include_deleted, &mut |entry| {
+            if !entry.is_directory() && filter(entry) {
+                let hash = hex(&entry.sha256()?);
+                writeln!(out, "{hash}  {}", self.relative_path(entry))?;
+            }
+            Ok(())
+        })?;
+        out.flush()?;
+        Ok(())
+    }
 
-#[derive(Debug)]
-pub struct VFF {
-    fd: Box<dyn ReadSeek>,
-    header: VFFHeader,
-    parsed_fat1: FAT,
-    data_offset: u64,
-}
+    /// Like `dump`, but reads and writes the files in parallel on the rayon thread pool.
+    ///
+    /// The VFF handle can't be shared between threads, so this first walks the tree on the
+    /// calling thread, creating the directories and resolving every file's chain into on-disk
+    /// extents. Each worker then opens its own handle to `source`, which must be the file this
+    /// VFF was opened from, and copies the extents out independently.
+    #[cfg(feature = "rayon")]
+    pub fn dump_parallel(
+        &self,
+        source: &Path,
+        dump_location: PathBuf,
+        include_deleted: bool,
+    ) -> Result<()> {
+        use rayon::prelude::*;
 
-impl VFF {
-    pub fn new<T: Read+Seek+std::fmt::Debug+'static>(fd: T) -> Result<(Rc<RefCell<Self>>, Directory)> {
-        let mut fd: Box<dyn ReadSeek> = Box::new(fd);
-        let mut header = [0u8; 0x10];
-        fd.read_exact(&mut header)?;
-        fd.seek(io::SeekFrom::Current(0x10))?; // Seek an aditional 0x10
-        let header = check_header(header)?;
-        let parsed_fat1 = FAT::new(&mut fd, &header)?;
-        let mut root_data = Vec::with_capacity(0x1000);
-        root_data.resize_with(0x1000, Default::default);
-        fd.read_exact(root_data.as_mut_slice())?;
-        let data_offset = fd.stream_position()?;
+        std::fs::create_dir_all(&dump_location)?;
+        let mut jobs: Vec<(PathBuf, Extents, u32, Option<SystemTime>)> = Vec::new();
+        let mut dirs = HashMap::new();
+        let mut paths = DumpPaths::default();
+        self.visit(include_deleted, &mut |entry| {
+            let mut out = dump_location.clone();
+            out.extend(
+                self.dump_relative_path(&mut dirs, entry, entry.full_name())
+                    .split('/'),
+            );
+            let out = self.claim_dump_path(&mut paths, out, entry);
+            if entry.is_directory() {
+                std::fs::create_dir_all(out)?;
+            } else if let Some(source) = &entry.source {
+                let extents = source
+                    .vff
+                    .borrow_mut()
+                    .file_extents(source.start, entry.size())?;
+                let modified = entry.metadata().modified.map(|m| m.to_system_time());
+                jobs.push((out, extents, entry.size(), modified));
+            }
+            Ok(())
+        })?;
+        jobs.par_iter().try_for_each_init(
+            || File::open(source),
+            |src, (out, extents, size, modified)| -> Result<()> {
+                let src = match src {
+                    Ok(src) => src,
+                    Err(e) => return Err(io::Error::new(e.kind(), e.to_string()).into()),
+                };
+                let mut data: Vec<u8> = Vec::new();
+                for (offset, len) in extents {
+                    src.seek(io::SeekFrom::Start(*offset))?;
+                    let pos = data.len();
+                    data.resize(pos + len, 0);
+                    src.read_exact(&mut data[pos..])?;
+                }
+                data.truncate(*size as usize);
+                let mut f = File::create(out)?;
+                f.write_all(&data)?;
+                if let Some(modified) = modified {
+                    f.set_modified(*modified)?;
+                }
+                Ok(())
+            },
+        )
+    }
 
-        let ret = Rc::new(RefCell::new(VFF {
-            fd,
-            header,
-            parsed_fat1,
-            data_offset,
-        }));
-        let root = Directory::new(ret.clone(), root_data, String::with_capacity(0))?;
-        Ok((ret, root))
+    /// Where `entry` goes relative to the dump directory, written as `name` below the
+    /// directories recorded in `dirs`. Each directory passed in is recorded, so its contents
+    /// must come after it, as they do in `visit`.
+    fn dump_relative_path(
+        &self,
+        dirs: &mut HashMap<String, String>,
+        entry: &DirectoryEntry,
+        name: &str,
+    ) -> String {
+        let name = self.safe_file_name(name, || self.relative_path(entry));
+        let path = match dirs.get(entry.path()) {
+            Some(parent) => parent.to_owned() + "/" + &name,
+            None => name,
+        };
+        if entry.is_directory() {
+            dirs.insert(
+                entry.path().to_owned() + "/" + entry.full_name(),
+                path.clone(),
+            );
+        }
+        path
     }
 
-    fn inner_read(&mut self, len: usize) -> Result<Vec<u8>> {
-        let mut ret: Vec<u8> = Vec::with_capacity(len);
-        ret.resize_with(len, Default::default);
-        self.fd.read_exact(ret.as_mut_slice())?;
-        Ok(ret)
+    /// `name`, escaped with a `Warning::UnsafeName` for the entry at `path` if it can't be
+    /// used as a file name
+    fn safe_file_name(&self, name: &str, path: impl FnOnce() -> String) -> String {
+        match escape_file_name(name) {
+            Some(escaped) => {
+                self.vff.borrow_mut().warnings.push(Warning::UnsafeName {
+                    path: path(),
+                    name: escaped.clone(),
+                });
+                escaped
+            }
+            None => name.to_owned(),
+        }
     }
 
-    pub fn read_cluster(&mut self, cluster_num: u32) -> Result<Vec<u8>> {
-        let cluster_num = cluster_num - 2;
-        let offset = self.data_offset + self.header.cluster_size as u64 * cluster_num as u64;
-        self.fd.seek(io::SeekFrom::Start(offset))?;
-        self.inner_read(self.header.cluster_size as usize)
+    /// Claim where `entry` is written in place of `out`, warning if it had to be renamed
+    fn claim_dump_path(
+        &self,
+        paths: &mut DumpPaths,
+        out: PathBuf,
+        entry: &DirectoryEntry,
+    ) -> PathBuf {
+        let actual = paths.claim(out.clone(), entry.is_directory());
+        if actual.file_name() != out.file_name() {
+            self.vff.borrow_mut().warnings.push(Warning::NameCollision {
+                path: self.relative_path(entry),
+                name: actual
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned(),
+            });
+        }
+        actual
     }
 
-    pub fn read_chain(&mut self, start: u32) -> Result<Vec<u8>> {
-        let clusters = self.parsed_fat1.get_chain(start)?;
-        let mut ret: Vec<u8> = Vec::new();
-        for cluster in clusters {
-            ret.extend(self.read_cluster(cluster)?);
+    /// The path of `entry` relative to this directory, without a leading slash
+    fn relative_path(&self, entry: &DirectoryEntry) -> String {
+        // Entry paths always start with our own path
+        let parent = entry.path()[self.path.len()..].trim_start_matches('/');
+        if parent.is_empty() {
+            entry.full_name().to_owned()
+        } else {
+            parent.to_owned() + "/" + entry.full_name()
         }
-        Ok(ret)
     }
+
+    /// Compare everything under this directory with everything under `other`, matching entries
+    /// by their relative path. Files of the same size are compared a piece at a time, so
+    /// neither has to be loaded in full. The differences come back sorted by path.
+    pub fn diff(&self, other: &Directory) -> Result<Vec<DiffEntry>> {
+        let mut ours = std::collections::BTreeMap::new();
+        self.visit(false, &mut |entry| {
+            ours.insert(self.relative_path(entry), entry.clone());
+            Ok(())
+        })?;
+        let mut theirs = std::collections::BTreeMap::new();
+        other.visit(false, &mut |entry| {
+            theirs.insert(other.relative_path(entry), entry.clone());
+            Ok(())
+        })?;
+        let mut ret = Vec::new();
+        for (path, entry) in &ours {
+            match theirs.get(path) {
+                None => ret.push(DiffEntry::Removed(path.clone())),
+                Some(their_entry) if Self::entries_differ(entry, their_entry)? => {
+                    ret.push(DiffEntry::Modified(path.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        for path in theirs.keys().filter(|path| !ours.contains_key(*path)) {
+            ret.push(DiffEntry::Added(path.clone()));
+        }
+        ret.sort_by(|a, b| a.path().cmp(b.path()));
+        Ok(ret)
+    }
+
+    fn entries_differ(a: &DirectoryEntry, b: &DirectoryEntry) -> Result<bool> {
+        if a.is_directory() || b.is_directory() {
+            return Ok(a.is_directory() != b.is_directory());
+        }
+        if a.size() != b.size() {
+            return Ok(true);
+        }
+        let (mut a, mut b) = (a.reader()?, b.reader()?);
+        let (mut buf_a, mut buf_b) = ([0u8; 0x1000], [0u8; 0x1000]);
+        loop {
+            let read = a.read(&mut buf_a)?;
+            if read == 0 {
+                return Ok(false);
+            }
+            b.read_exact(&mut buf_b[..read])?;
+            if buf_a[..read] != buf_b[..read] {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Write everything under this directory to a tar archive, using the FAT modification
+    /// times as the entry mtimes
+    #[cfg(feature = "tar")]
+    pub fn export_tar<W: Write>(&self, out: W, include_deleted: bool) -> Result<()> {
+        self.export_tar_matching(out, include_deleted, &|_| true)
+    }
+
+    /// Like `export_tar`, only writing the entries `filter` accepts. Matching files keep their
+    /// full relative path, so the directories leading to them don't need to match.
+    #[cfg(feature = "tar")]
+    pub fn export_tar_matching<W: Write>(
+        &self,
+        out: W,
+        include_deleted: bool,
+        filter: &dyn Fn(&DirectoryEntry) -> bool,
+    ) -> Result<()> {
+        let mut builder = tar::Builder::new(out);
+        self.visit(include_deleted, &mut |entry| {
+            if !filter(entry) {
+                return Ok(());
+            }
+            let mut header = tar::Header::new_gnu();
+            if let Some(modified) = entry.metadata().modified {
+                header.set_mtime(modified.to_unix_timestamp().max(0) as u64);
+            }
+            let path = self.relative_path(entry);
+            if entry.is_directory() {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                builder.append_data(&mut header, path, io::empty())?;
+            } else {
+                header.set_mode(0o644);
+                header.set_size(entry.size() as u64);
+                builder.append_data(&mut header, path, entry.reader()?)?;
+            }
+            Ok(())
+        })?;
+        builder.into_inner()?.flush()?;
+        Ok(())
+    }
+
+    /// Write everything under this directory to a zip archive, using the FAT modification
+    /// times as the entry dates. Deleted files get a `.deleted` suffix.
+    #[cfg(feature = "zip")]
+    pub fn export_zip<W: Write + Seek>(&self, out: W, include_deleted: bool) -> Result<()> {
+        use zip::write::SimpleFileOptions;
+        let mut writer = zip::ZipWriter::new(out);
+        self.visit(include_deleted, &mut |entry| {
+            let mut options = SimpleFileOptions::default();
+            if let Some(modified) = entry.metadata().modified {
+                if let Ok(time) = zip::DateTime::from_date_and_time(
+                    modified.year,
+                    modified.month,
+                    modified.day,
+                    modified.hour,
+                    modified.minute,
+                    modified.second,
+                ) {
+                    options = options.last_modified_time(time);
+                }
+            }
+            let mut path = self.relative_path(entry);
+            if entry.is_directory() {
+                writer
+                    .add_directory(path, options)
+                    .map_err(io::Error::from)?;
+            } else {
+                if entry.is_deleted() {
+                    path.push_str(".deleted");
+                }
+                writer.start_file(path, options).map_err(io::Error::from)?;
+                io::copy(&mut entry.reader()?, &mut writer)?;
+            }
+            Ok(())
+        })?;
+        writer.finish().map_err(io::Error::from)?.flush()?;
+        Ok(())
+    }
+}
+
+/// The same lines as `ls(false)`, so `println!("{dir}")` prints a listing. If the tree can't
+/// be read, the error is written instead, as `<unreadable: ...>`.
+impl std::fmt::Display for Directory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.ls(false) {
+            Ok(listing) => write!(f, "{}", listing.join("\n")),
+            Err(e) => write!(f, "<unreadable: {e}>"),
+        }
+    }
+}
+
+/// Parse the raw contents of a directory into its entries, pairing up long file names
+fn parse_directory(data: &[u8], show_deleted: bool) -> Result<Vec<ParsedFATEntry>> {
+    Ok(parse_directory_slots(data, show_deleted)?
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .collect())
+}
+
+/// Like `parse_directory`, also returning which 32 byte slots of `data` each entry takes up,
+/// including its long name slots
+fn parse_directory_slots(
+    data: &[u8],
+    show_deleted: bool,
+) -> Result<Vec<(Range<usize>, ParsedFATEntry)>> {
+    let mut files = Vec::new();
+    let mut long_name = LongNameBuilder::default();
+    for (slot, chunk) in data.chunks_exact(32).enumerate() {
+        let mut chunk = <[u8; 32]>::try_from(chunk).unwrap(); // Won't panic because we got our slice from chunks_exact
+        let mut parsed_entry = ParsedFATEntry::from_slice(&mut chunk)?;
+        match parsed_entry.name[0] {
+            0x0 => {
+                long_name.clear();
+                continue;
+            } //free entry marker
+            0xe5 => {
+                //deleted entry marker
+                if !show_deleted {
+                    long_name.clear();
+                    continue;
+                }
+                parsed_entry.deleted = true;
+            }
+            _ => {}
+        }
+        if parsed_entry.attr & 0xf == 0xf {
+            long_name.push(&chunk);
+            continue;
+        }
+        // The volume label isn't a file, see VFF::volume_label
+        if parsed_entry.is_volume_label() {
+            long_name.clear();
+            continue;
+        }
+        let pending = long_name.slots.len();
+        parsed_entry.long_name = long_name.finish(&parsed_entry);
+        let first = match parsed_entry.long_name {
+            Some(_) => slot - pending,
+            None => slot,
+        };
+        files.push((first..slot + 1, parsed_entry));
+    }
+    Ok(files)
+}
+
+/// A case-insensitive glob for matching VFF paths.
+///
+/// `?` matches any one character and `*` any run of characters, neither crossing a `/`, while `**`
+/// also matches across directories. A pattern with no `/` is matched against the file name alone,
+/// so `*.LOG` finds logs anywhere in the tree.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    pattern: Vec<char>,
+    anchored: bool,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Self {
+        Glob {
+            pattern: pattern.trim_start_matches('/').chars().collect(),
+            anchored: pattern.contains('/'),
+        }
+    }
+
+    /// Whether `path`, a full VFF path like `/2022/10/PLAY.LOG`, matches
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path.trim_start_matches('/');
+        let path = match self.anchored {
+            true => path,
+            false => path.rsplit('/').next().unwrap_or(path),
+        };
+        glob_match(&self.pattern, &path.chars().collect::<Vec<_>>())
+    }
+
+    /// Whether `entry` matches, going by its full VFF path
+    pub fn matches_entry(&self, entry: &DirectoryEntry) -> bool {
+        self.matches(&(entry.path().to_owned() + "/" + entry.full_name()))
+    }
+}
+
+fn glob_match(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        // `**/` can also match no directories at all
+        ['*', '*', '/', rest @ ..] if glob_match(rest, path) => true,
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        ['*', rest @ ..] => {
+            let run = path.iter().position(|&c| c == '/').unwrap_or(path.len());
+            (0..=run).any(|i| glob_match(rest, &path[i..]))
+        }
+        ['?', rest @ ..] => {
+            matches!(path.first(), Some(&c) if c != '/') && glob_match(rest, &path[1..])
+        }
+        [p, rest @ ..] => {
+            matches!(path.first(), Some(c) if c.eq_ignore_ascii_case(p))
+                && glob_match(rest, &path[1..])
+        }
+    }
+}
+
+/// Where the slots of a directory are stored
+#[derive(Debug)]
+enum DirLocation {
+    Root,
+    Chain(Arc<[u32]>),
+}
+
+/// An entry found by `VFF::locate`, along with where it's stored so it can be modified
+#[derive(Debug)]
+struct Located {
+    entry: ParsedFATEntry,
+    /// The slots the entry takes up in its directory, the 8.3 entry is the last one
+    slots: Range<usize>,
+    dir: DirLocation,
+}
+
+/// Settings for opening a VFF whose layout or condition doesn't suit `VFF::new`.
+///
+/// ```no_run
+/// # fn main() -> wiivff::Result<()> {
+/// let nand = std::fs::File::open("nand.bin")?;
+/// let (vff, root_dir) = wiivff::VffOptions::new()
+///     .base_offset(0x1234)
+///     .lenient(true)
+///     .open(nand)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct VffOptions {
+    base_offset: u64,
+    cluster_size_multiplier: u16,
+    header_endianness: Endianness,
+    lazy_fat: bool,
+    lenient: bool,
+    name_encoding: NameEncoding,
+    read_secondary_fat: bool,
+    root_dir_size: u64,
+    validate_preamble: bool,
+}
+
+impl Default for VffOptions {
+    fn default() -> Self {
+        VffOptions {
+            base_offset: 0,
+            cluster_size_multiplier: DEFAULT_CLUSTER_SIZE_MULTIPLIER,
+            header_endianness: Endianness::Big,
+            lazy_fat: false,
+            lenient: false,
+            name_encoding: NameEncoding::Utf8Lossy,
+            read_secondary_fat: false,
+            root_dir_size: DEFAULT_ROOT_DIR_SIZE,
+            validate_preamble: true,
+        }
+    }
+}
+
+impl VffOptions {
+    /// The options `VFF::new` uses
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Where the VFF starts in the input, such as inside a larger NAND dump
+    pub fn base_offset(mut self, base_offset: u64) -> Self {
+        self.base_offset = base_offset;
+        self
+    }
+
+    /// What the header's cluster size field is multiplied by to get the cluster size in bytes.
+    /// The Wii uses 16. Must be a power of two.
+    pub fn cluster_size_multiplier(mut self, cluster_size_multiplier: u16) -> Self {
+        self.cluster_size_multiplier = cluster_size_multiplier;
+        self
+    }
+
+    /// The byte order of the header's volume size, cluster size and unknown word. The magic
+    /// reads the same either way.
+    pub fn header_endianness(mut self, header_endianness: Endianness) -> Self {
+        self.header_endianness = header_endianness;
+        self
+    }
+
+    /// Don't read the FAT until something needs it, so looking at just the header or the root
+    /// directory of a large image is quick. Problems with the FAT then show up as errors on
+    /// first use rather than when opening, and in lenient mode bad reserved entries aren't
+    /// reported.
+    pub fn lazy_fat(mut self, lazy_fat: bool) -> Self {
+        self.lazy_fat = lazy_fat;
+        self
+    }
+
+    /// Start out in lenient mode, see `VFF::set_lenient`
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// How to decode 8.3 names, see `NameEncoding`
+    pub fn name_encoding(mut self, name_encoding: NameEncoding) -> Self {
+        self.name_encoding = name_encoding;
+        self
+    }
+
+    /// Follow chains through the second copy of the FAT, for when the first is damaged.
+    /// `VFF::fat` then returns the second copy and `VFF::backup_fat` the first.
+    pub fn read_secondary_fat(mut self, read_secondary_fat: bool) -> Self {
+        self.read_secondary_fat = read_secondary_fat;
+        self
+    }
+
+    /// The size of the root directory in bytes, see `VFF::new_with_root_size`
+    pub fn root_dir_size(mut self, root_dir_size: u64) -> Self {
+        self.root_dir_size = root_dir_size;
+        self
+    }
+
+    /// Refuse a VFF whose preamble is neither zero padding nor a copy of the header, which is
+    /// all that's understood of it so far. On by default. Turn it off to open files with other
+    /// preambles and look at them with `VFFHeader::raw_preamble2`.
+    pub fn validate_preamble(mut self, validate_preamble: bool) -> Self {
+        self.validate_preamble = validate_preamble;
+        self
+    }
+
+    pub fn open<T: Read + Seek + std::fmt::Debug + 'static>(
+        &self,
+        fd: T,
+    ) -> Result<(Rc<RefCell<VFF>>, Directory)> {
+        let fd: Box<dyn ReadSeek> = Box::new(fd);
+        VFF::open_with(Handle::ReadOnly(fd), self)
+    }
+
+    /// Like `open`, keeping `fd` writable so the VFF can be modified, see `VFF::write_file`
+    pub fn open_writable<T: Read + Write + Seek + std::fmt::Debug + 'static>(
+        &self,
+        fd: T,
+    ) -> Result<(Rc<RefCell<VFF>>, Directory)> {
+        let fd: Box<dyn ReadWriteSeek> = Box::new(fd);
+        VFF::open_with(Handle::Writable(fd), self)
+    }
+
+    /// Like `open`, but without a root `Directory`, which would tie the VFF to this thread.
+    /// `fd` has to be `Send`, so the VFF can be moved to another thread or shared behind an
+    /// `Arc<Mutex<VFF<Sendable>>>`, and read with `VFF::read_path` and `VFF::list_path`.
+    pub fn open_owned<T: Read + Seek + std::fmt::Debug + Send + 'static>(
+        &self,
+        fd: T,
+    ) -> Result<VFF<Sendable>> {
+        let fd: Box<dyn ReadSeek + Send> = Box::new(fd);
+        let (vff, _) = VFF::open_parts(Handle::ReadOnly(fd), self)?;
+        Ok(vff)
+    }
+}
+
+mod input {
+    use super::*;
+
+    pub trait ReadSeek: Read + Seek + std::fmt::Debug {}
+    impl<T> ReadSeek for T where T: Read + Seek + std::fmt::Debug {}
+    pub trait ReadWriteSeek: ReadSeek + Write {}
+    impl<T> ReadWriteSeek for T where T: Read + Write + Seek + std::fmt::Debug {}
+
+    /// How a VFF holds its input. Only `Local` and `Sendable` implement this.
+    pub trait Backing {
+        type Reader: ?Sized + ReadSeek;
+        type Writer: ?Sized + ReadWriteSeek;
+        fn reader(fd: &mut Self::Reader) -> &mut dyn ReadSeek;
+        fn writer(fd: &mut Self::Writer) -> &mut dyn ReadWriteSeek;
+    }
+}
+use input::{Backing, ReadSeek, ReadWriteSeek};
+
+/// The usual backing of a `VFF`, which takes any input but keeps the VFF on one thread
+#[derive(Debug)]
+pub struct Local;
+
+impl Backing for Local {
+    type Reader = dyn ReadSeek;
+    type Writer = dyn ReadWriteSeek;
+    fn reader(fd: &mut Self::Reader) -> &mut dyn ReadSeek {
+        fd
+    }
+    fn writer(fd: &mut Self::Writer) -> &mut dyn ReadWriteSeek {
+        fd
+    }
+}
+
+/// The backing of a `VFF` opened with `VffOptions::open_owned`, whose input is `Send` so the
+/// VFF can be too
+#[derive(Debug)]
+pub struct Sendable;
+
+impl Backing for Sendable {
+    type Reader = dyn ReadSeek + Send;
+    type Writer = dyn ReadWriteSeek + Send;
+    fn reader(fd: &mut Self::Reader) -> &mut dyn ReadSeek {
+        fd
+    }
+    fn writer(fd: &mut Self::Writer) -> &mut dyn ReadWriteSeek {
+        fd
+    }
+}
+
+/// The underlying input, which can only be written to if the VFF was opened for writing
+#[derive(Debug)]
+enum Handle<B: Backing> {
+    ReadOnly(Box<B::Reader>),
+    Writable(Box<B::Writer>),
+}
+
+impl<B: Backing> Handle<B> {
+    fn reader(&mut self) -> &mut dyn ReadSeek {
+        match self {
+            Handle::ReadOnly(fd) => B::reader(fd),
+            Handle::Writable(fd) => B::writer(fd),
+        }
+    }
+
+    fn writer(&mut self) -> Result<&mut dyn ReadWriteSeek> {
+        match self {
+            Handle::ReadOnly(_) => Err(VFFError::Other(
+                "The VFF was opened read only, see VffOptions::open_writable".to_owned(),
+            )),
+            Handle::Writable(fd) => Ok(B::writer(fd)),
+        }
+    }
+}
+
+/// An opened VFF. `B` is `Local` unless it was opened with `VffOptions::open_owned`, which
+/// gives a `VFF<Sendable>` that can be sent to other threads but not read through `Directory`.
+#[derive(Debug)]
+pub struct VFF<B: Backing = Local> {
+    /// Behind a RefCell so that reads, which only need to seek, can take &self
+    fd: RefCell<Handle<B>>,
+    header: VFFHeader,
+    /// Read while opening, or on first use with `VffOptions::lazy_fat`
+    fats: OnceCell<Fats>,
+    base_offset: u64,
+    data_offset: u64,
+    root_size: u64,
+    /// Chains already walked in the primary FAT, keyed by their starting cluster. Cleared
+    /// whenever the FAT is modified.
+    chain_cache: HashMap<u32, Arc<[u32]>>,
+    /// Whether broken chains are cut short with a warning instead of failing the read
+    lenient: bool,
+    name_encoding: NameEncoding,
+    read_secondary_fat: bool,
+    warnings: Vec<Warning>,
+}
+
+/// The two FAT copies. `primary` is the one chains are followed through, which is the second
+/// copy in the input with `VffOptions::read_secondary_fat`.
+#[derive(Debug)]
+struct Fats {
+    primary: FAT,
+    backup: FAT,
+}
+
+impl VFF {
+    /// Open a VFF with the default `VffOptions`
+    pub fn new<T: Read + Seek + std::fmt::Debug + 'static>(
+        fd: T,
+    ) -> Result<(Rc<RefCell<Self>>, Directory)> {
+        VffOptions::new().open(fd)
+    }
+
+    /// Open a VFF that starts `base_offset` bytes into `fd`, such as one inside a larger NAND dump
+    pub fn new_at_offset<T: Read + Seek + std::fmt::Debug + 'static>(
+        fd: T,
+        base_offset: u64,
+    ) -> Result<(Rc<RefCell<Self>>, Directory)> {
+        VffOptions::new().base_offset(base_offset).open(fd)
+    }
+
+    /// Like `new_at_offset`, for images whose root directory isn't the usual size. The header
+    /// doesn't record the root directory size, so it can't be worked out automatically.
+    pub fn new_with_root_size<T: Read + Seek + std::fmt::Debug + 'static>(
+        fd: T,
+        base_offset: u64,
+        root_size: u64,
+    ) -> Result<(Rc<RefCell<Self>>, Directory)> {
+        VffOptions::new()
+            .base_offset(base_offset)
+            .root_dir_size(root_size)
+            .open(fd)
+    }
+
+    fn open_with(
+        handle: Handle<Local>,
+        options: &VffOptions,
+    ) -> Result<(Rc<RefCell<Self>>, Directory)> {
+        let (vff, root_data) = Self::open_parts(handle, options)?;
+        let vff = Rc::new(RefCell::new(vff));
+        let root = Directory::root(vff.clone(), root_data)?;
+        Ok((vff, root))
+    }
+
+    /// Open a VFF that is already in memory
+    pub fn from_bytes(data: Vec<u8>) -> Result<(Rc<RefCell<Self>>, Directory)> {
+        Self::new(io::Cursor::new(data))
+    }
+
+    /// Open the VFF file at `path`
+    pub fn from_path(path: impl AsRef<Path>) -> Result<(Rc<RefCell<Self>>, Directory)> {
+        Self::new(File::open(path)?)
+    }
+
+    /// Open the VFF file at `path` for reading and writing
+    pub fn from_path_writable(path: impl AsRef<Path>) -> Result<(Rc<RefCell<Self>>, Directory)> {
+        let file = File::options().read(true).write(true).open(path)?;
+        VffOptions::new().open_writable(file)
+    }
+
+    /// Create a new, empty VFF at `path` and open it for writing. `path` must not exist yet.
+    ///
+    /// `cluster_size` is in bytes and has to be a power of two of at least 16, the unit the
+    /// header stores it in. The volume must hold enough clusters to be FAT16, which is all this supports.
+    pub fn create(
+        path: impl AsRef<Path>,
+        volume_size: u32,
+        cluster_size: u16,
+    ) -> Result<(Rc<RefCell<Self>>, Directory)> {
+        if cluster_size < 16 || !cluster_size.is_power_of_two() {
+            return Err(VFFError::Other(format!(
+                "The cluster size must be a power of two of at least 16, not {cluster_size:#x}"
+            )));
+        }
+        let cluster_count = volume_size / cluster_size as u32;
+        if !(FAT12_MAX_CLUSTERS + 1..=FAT16_MAX_CLUSTERS).contains(&cluster_count) {
+            return Err(VFFError::Other(format!(
+                "{cluster_count:#x} clusters is outside the FAT16 range of {:#x} to {FAT16_MAX_CLUSTERS:#x}",
+                FAT12_MAX_CLUSTERS + 1
+            )));
+        }
+        let fat_size = FAT::table_size(cluster_count * 2, cluster_size as u32) as usize;
+        let root_size = VffOptions::new().root_dir_size;
+        let mut metadata = vec![0u8; 0x20 + 2 * fat_size + root_size as usize];
+        metadata[0..4].copy_from_slice(&EXPECTED_FILE_MAGIC);
+        metadata[8..12].copy_from_slice(&volume_size.to_be_bytes());
+        metadata[12..14].copy_from_slice(&(cluster_size / 16).to_be_bytes());
+        // The two reserved entries at the start of each FAT copy
+        for fat_offset in [0x20, 0x20 + fat_size] {
+            metadata[fat_offset..fat_offset + 4].copy_from_slice(&[0xf8, 0xff, 0xff, 0xff]);
+        }
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.write_all(&metadata)?;
+        file.set_len(u64::from(volume_size).max(metadata.len() as u64))?;
+        VffOptions::new().open_writable(file)
+    }
+
+    /// Open the gzip compressed VFF file at `path`.
+    ///
+    /// A gzip stream can't be seeked, so the whole image is decompressed into memory first and
+    /// stays there while the VFF is open. Expect to use as much memory as the uncompressed size.
+    #[cfg(feature = "flate2")]
+    pub fn from_gz(path: impl AsRef<Path>) -> Result<(Rc<RefCell<Self>>, Directory)> {
+        let mut data = Vec::new();
+        flate2::read::MultiGzDecoder::new(io::BufReader::new(File::open(path)?))
+            .read_to_end(&mut data)?;
+        Self::from_bytes(data)
+    }
+
+    /// Open the VFF file at `path` by memory mapping it instead of reading through `File`.
+    ///
+    /// The file must not be modified by anything else while the VFF is open.
+    #[cfg(feature = "mmap")]
+    pub fn from_mmap(path: impl AsRef<Path>) -> Result<(Rc<RefCell<Self>>, Directory)> {
+        let file = File::open(path)?;
+        // SAFETY: The map is read only, and concurrent modification is ruled out by the doc contract
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Self::new(io::Cursor::new(map))
+    }
+
+    /// Read the directory whose chain starts at `start_cluster`, such as one found by scanning
+    /// for orphaned chains. `path` is what the entries under it are listed as, e.g. `/FOUND`.
+    ///
+    /// The chain is followed leniently if `set_lenient` was used.
+    pub fn read_directory(
+        vff: &Rc<RefCell<Self>>,
+        start_cluster: u32,
+        path: String,
+    ) -> Result<Directory> {
+        let data = vff.borrow_mut().read_entry_chain(start_cluster)?;
+        let mut dir = Directory::new(vff.clone(), data, path)?;
+        dir.ancestors.push(start_cluster);
+        Ok(dir)
+    }
+
+    /// List the entries directly inside the directory at `path`, without recursing. `.` and
+    /// `..` are left out, an empty path or `/` lists the root directory.
+    pub fn list_dir(
+        vff: &Rc<RefCell<Self>>,
+        path: &str,
+        include_deleted: bool,
+    ) -> Result<Vec<DirectoryEntry>> {
+        let root_data = vff.borrow().read_root()?;
+        let root = Directory::root(vff.clone(), root_data)?;
+        let entry = root.get_path(path)?;
+        match entry.content() {
+            DirectoryContent::Dir(dir) => dir.entries(include_deleted, false),
+            DirectoryContent::NoContent => Err(VFFError::Other(format!("{path} not found"))),
+            DirectoryContent::File(_) | DirectoryContent::Unloaded => Err(VFFError::Other(
+                format!("{path} is a file, not a directory"),
+            )),
+        }
+    }
+}
+
+impl<B: Backing> VFF<B> {
+    /// Open the VFF in `handle`, returning it along with the root directory's data
+    fn open_parts(mut handle: Handle<B>, options: &VffOptions) -> Result<(Self, Vec<u8>)> {
+        let base_offset = options.base_offset;
+        let root_size = options.root_dir_size;
+        if root_size == 0 || !root_size.is_multiple_of(32) {
+            return Err(VFFError::InvalidData {
+                context: "Opening the VFF: the root directory size".to_owned(),
+                expected: "A root directory size that is a nonzero multiple of 32".to_owned(),
+                found: format!("{root_size:#x}"),
+                offset: None,
+            });
+        }
+        let multiplier = options.cluster_size_multiplier;
+        if !multiplier.is_power_of_two() {
+            return Err(VFFError::InvalidData {
+                context: "Opening the VFF: the cluster size multiplier".to_owned(),
+                expected: "A power of two".to_owned(),
+                found: multiplier.to_string(),
+                offset: None,
+            });
+        }
+        let fd = handle.reader();
+        let available = fd.seek(io::SeekFrom::End(0))?.saturating_sub(base_offset);
+        // Catch truncated input up front, rather than with an UnexpectedEof partway through
+        let require = |region: &str, end: u64| match available < end {
+            true => Err(VFFError::InvalidData {
+                context: format!("Opening the VFF: reading the {region}"),
+                expected: format!("At least {end:#x} bytes"),
+                found: format!("{available:#x} bytes"),
+                offset: Some(base_offset + available),
+            }),
+            false => Ok(()),
+        };
+        fd.seek(io::SeekFrom::Start(base_offset))?;
+        require("header", 0x10)?;
+        let mut raw_header = [0u8; 0x10];
+        fd.read_exact(&mut raw_header)?;
+        require("preamble", 0x20)?;
+        let mut preamble = [0u8; 0x10];
+        fd.read_exact(&mut preamble)?;
+        let header = check_header(
+            raw_header,
+            preamble,
+            base_offset,
+            options.header_endianness,
+            multiplier,
+        )?;
+        // Only once the header is known to be a VFF's, so other files fail on the magic
+        if options.validate_preamble {
+            check_preamble(&raw_header, &preamble, base_offset)?;
+        }
+        let mut warnings = Vec::new();
+        if !header
+            .volume_size
+            .is_multiple_of(header.cluster_size as u32)
+        {
+            warnings.push(Warning::PartialCluster {
+                volume_size: header.volume_size,
+                cluster_size: header.cluster_size,
+            });
+        }
+        let fat_size = FAT::fat16_table_size(&header)?;
+        require("FAT", 0x20 + 2 * fat_size as u64)?;
+        // The data area follows directly, so this is also where cluster 2 begins
+        let data_start = 0x20 + 2 * fat_size as u64 + root_size;
+        require("root directory", data_start)?;
+        let fats = match options.lazy_fat {
+            true => OnceCell::new(),
+            false => {
+                let (loaded, fat_warnings) = Self::read_fats(
+                    fd,
+                    &header,
+                    base_offset,
+                    options.lenient,
+                    options.read_secondary_fat,
+                )?;
+                warnings.extend(fat_warnings);
+                OnceCell::from(loaded)
+            }
+        };
+        fd.seek(io::SeekFrom::Start(
+            base_offset + 0x20 + 2 * fat_size as u64,
+        ))?;
+        let mut root_data = vec![0u8; root_size as usize];
+        fd.read_exact(root_data.as_mut_slice())?;
+        // Not checked against the end of the input: volume_size counts the header and FATs too,
+        // so the last few clusters of a well formed VFF are always past it
+        let data_offset = base_offset + data_start;
+
+        let vff = VFF {
+            fd: RefCell::new(handle),
+            header,
+            fats,
+            base_offset,
+            data_offset,
+            root_size,
+            chain_cache: HashMap::new(),
+            lenient: options.lenient,
+            name_encoding: options.name_encoding,
+            read_secondary_fat: options.read_secondary_fat,
+            warnings,
+        };
+        Ok((vff, root_data))
+    }
+
+    /// Read both FAT copies. Bad reserved entries fail the read, or are returned as warnings
+    /// if `lenient` is set.
+    fn read_fats(
+        mut fd: &mut dyn ReadSeek,
+        header: &VFFHeader,
+        base_offset: u64,
+        lenient: bool,
+        read_secondary_fat: bool,
+    ) -> Result<(Fats, Vec<Warning>)> {
+        let fat_size = FAT::fat16_table_size(header)?;
+        fd.seek(io::SeekFrom::Start(base_offset + 0x20))?;
+        let mut primary = FAT::new(&mut fd, header)?;
+        primary.offset = Some(base_offset + 0x20);
+        // The backup copy immediately follows the primary
+        let mut backup = FAT::new(&mut fd, header)?;
+        backup.offset = Some(base_offset + 0x20 + fat_size as u64);
+        let mut warnings = Vec::new();
+        // A blank or non-FAT region after a valid looking header shows up here
+        for (fat, copy) in [(&primary, FatCopy::Primary), (&backup, FatCopy::Backup)] {
+            if let Err(e) = fat.check_reserved() {
+                if !lenient {
+                    return Err(e);
+                }
+                warnings.push(Warning::ReservedEntries {
+                    copy,
+                    values: [fat.clusters[0].into(), fat.clusters[1].into()],
+                });
+            }
+        }
+        if read_secondary_fat {
+            std::mem::swap(&mut primary, &mut backup);
+        }
+        Ok((Fats { primary, backup }, warnings))
+    }
+
+    /// The FATs, reading them first if the VFF was opened with `VffOptions::lazy_fat`
+    fn fats(&self) -> Result<&Fats> {
+        if let Some(fats) = self.fats.get() {
+            return Ok(fats);
+        }
+        let (loaded, _) = Self::read_fats(
+            self.fd.borrow_mut().reader(),
+            &self.header,
+            self.base_offset,
+            self.lenient,
+            self.read_secondary_fat,
+        )?;
+        Ok(self.fats.get_or_init(|| loaded))
+    }
+
+    fn fats_mut(&mut self) -> Result<&mut Fats> {
+        self.fats()?;
+        Ok(self.fats.get_mut().expect("the FATs were just read"))
+    }
+
+    /// Whether the FAT has been read yet, which only waits for first use with
+    /// `VffOptions::lazy_fat`
+    pub fn fat_loaded(&self) -> bool {
+        self.fats.get().is_some()
+    }
+
+    pub fn header(&self) -> &VFFHeader {
+        &self.header
+    }
+
+    /// The primary FAT. Only fails if the VFF was opened with `VffOptions::lazy_fat` and the
+    /// FAT can't be read.
+    pub fn fat(&self) -> Result<&FAT> {
+        Ok(&self.fats()?.primary)
+    }
+
+    /// The backup FAT, which can fail like `fat`
+    pub fn backup_fat(&self) -> Result<&FAT> {
+        Ok(&self.fats()?.backup)
+    }
+
+    /// The state of every entry of the FAT, for drawing an allocation map. Indexed by cluster
+    /// number, without the padding after the last cluster.
+    pub fn fat_map(&self) -> Result<Vec<ClusterState>> {
+        let fat = self.fat()?;
+        let end = (fat.cluster_count as usize).min(fat.clusters.len());
+        Ok(fat.clusters[..end]
+            .iter()
+            .enumerate()
+            .map(|(cluster, &value)| {
+                let value = value as u32;
+                match cluster {
+                    0 | 1 => ClusterState::Reserved,
+                    _ if fat.is_available(value) => ClusterState::Free,
+                    _ if fat.is_used(value) => ClusterState::Used,
+                    _ if fat.is_bad(value) => ClusterState::Bad,
+                    _ => ClusterState::Eoc,
+                }
+            })
+            .collect())
+    }
+
+    /// A fingerprint of the header fields and the primary FAT, but not of any file data, so
+    /// images with the same layout hash the same. This is 64 bit FNV-1a, which won't change
+    /// between versions.
+    pub fn structure_hash(&self) -> Result<u64> {
+        let header = &self.header;
+        let fields = [
+            &header.unknown.to_be_bytes()[..],
+            &header.volume_size.to_be_bytes(),
+            &header.cluster_size.to_be_bytes(),
+        ];
+        let table = self.fat()?.as_slice().iter().map(|x| x.to_le_bytes());
+        let hash = fields
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain(table.flatten())
+            .fold(0xcbf29ce484222325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+        Ok(hash)
+    }
+
+    /// The clusters the primary FAT marks bad, see `FAT::bad_clusters`
+    pub fn bad_clusters(&self) -> Result<Vec<u32>> {
+        Ok(self.fat()?.bad_clusters())
+    }
+
+    pub fn free_space_bytes(&self) -> Result<u64> {
+        Ok(self.fat()?.free_cluster_count() as u64 * self.header.cluster_size as u64)
+    }
+
+    /// Where the VFF begins within the underlying reader
+    pub fn base_offset(&self) -> u64 {
+        self.base_offset
+    }
+
+    /// The size of the root directory region in bytes
+    pub fn root_dir_size(&self) -> u64 {
+        self.root_size
+    }
+
+    /// How many 32 byte entries the root directory has room for. Unlike a subdirectory it
+    /// can't grow, so this is a hard limit.
+    pub fn root_capacity(&self) -> usize {
+        (self.root_size / 32) as usize
+    }
+
+    /// How many root directory slots are taken, counting long name slots and the volume label
+    /// but not free or deleted slots
+    pub fn root_used(&self) -> Result<usize> {
+        Ok(self
+            .read_root()?
+            .chunks_exact(32)
+            .filter(|slot| !matches!(slot[0], 0x0 | 0xe5))
+            .count())
+    }
+
+    /// Read directories and files leniently, see `read_chain_lenient`
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Problems worked around by lenient reads so far
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Compare the two FAT copies, returning the cluster indices where they disagree
+    pub fn verify_fats(&self) -> Result<Vec<u32>> {
+        let fats = self.fats()?;
+        let (fat1, fat2) = (&fats.primary, &fats.backup);
+        if fat1.fattype != fat2.fattype || fat1.clusters.len() != fat2.clusters.len() {
+            return Err(VFFError::InvalidData {
+                context: "VFF::verify_fats".to_owned(),
+                expected: "Both FAT copies should have the same type and size".to_owned(),
+                found: format!(
+                    "{:?} with {} entries vs {:?} with {} entries",
+                    fat1.fattype,
+                    fat1.clusters.len(),
+                    fat2.fattype,
+                    fat2.clusters.len()
+                ),
+                offset: None,
+            });
+        }
+        Ok(fat1
+            .clusters
+            .iter()
+            .zip(fat2.clusters.iter())
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, _)| i as u32)
+            .collect())
+    }
+
+    /// Read the file at `path`, without going through `Directory`
+    pub fn read_path(&mut self, path: &str) -> Result<Vec<u8>> {
+        let located = self.locate(path)?;
+        if located.entry.attr & DirectoryFlags::A_DIR != 0 {
+            return Err(VFFError::Other(format!("{path} is a directory")));
+        }
+        self.read_file(located.entry.start.into(), located.entry.size)
+    }
+
+    /// The entries directly inside the directory at `path`, like `list_dir` but without going
+    /// through `Directory`
+    pub fn list_path(&mut self, path: &str, include_deleted: bool) -> Result<Vec<ParsedFATEntry>> {
+        let data = if path.split('/').all(str::is_empty) {
+            self.read_root()?
+        } else {
+            let located = self.locate(path)?;
+            if located.entry.attr & DirectoryFlags::A_DIR == 0 {
+                return Err(VFFError::Other(format!(
+                    "{path} is a file, not a directory"
+                )));
+            }
+            self.read_entry_chain(located.entry.start.into())?
+        };
+        Ok(parse_directory(&data, include_deleted)?
+            .into_iter()
+            .filter(|entry| !entry.is_dot_or_dotdot())
+            .collect())
+    }
+
+    fn inner_read(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut ret = vec![0u8; len];
+        self.inner_read_into(offset, &mut ret)?;
+        Ok(ret)
+    }
+
+    /// Fill `buf` from `offset` in the underlying reader
+    fn inner_read_into(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut fd = self.fd.borrow_mut();
+        let fd = fd.reader();
+        fd.seek(io::SeekFrom::Start(offset))?;
+        fd.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn inner_write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let mut fd = self.fd.borrow_mut();
+        let fd = fd.writer()?;
+        fd.seek(io::SeekFrom::Start(offset))?;
+        fd.write_all(data)?;
+        Ok(())
+    }
+
+    pub fn read_cluster(&self, cluster_num: u32) -> Result<Vec<u8>> {
+        let mut ret = vec![0u8; self.header.cluster_size as usize];
+        self.read_cluster_run(&[cluster_num], &mut ret)?;
+        Ok(ret)
+    }
+
+    /// Where a data cluster starts in the underlying reader. The cluster number usually comes
+    /// straight from the input, so it's checked before it's used.
+    fn cluster_offset(&self, cluster_num: u32) -> Result<u64> {
+        let offset = Some(cluster_num)
+            .filter(|cluster| (2..self.header.cluster_count).contains(cluster))
+            .and_then(|cluster| (self.header.cluster_size as u64).checked_mul(cluster as u64 - 2))
+            .and_then(|offset| offset.checked_add(self.data_offset));
+        offset.ok_or_else(|| VFFError::InvalidData {
+            context: "Locating a data cluster".to_owned(),
+            expected: format!(
+                "A cluster number from 2 to {:04x}",
+                self.header.cluster_count.saturating_sub(1)
+            ),
+            found: format!("{cluster_num:04x}"),
+            offset: None,
+        })
+    }
+
+    /// Where the first cluster of the file starting at `start` begins in the underlying reader,
+    /// counting any `base_offset`, for pointing other tools such as a hex editor at it
+    pub fn file_offset(&self, start: u32) -> Result<u64> {
+        self.cluster_offset(start)
+    }
+
+    /// Where a run of consecutive clusters starts in the underlying reader. The last cluster is
+    /// checked as well as the first, so a run into the FAT's padding entries isn't read past
+    /// the end of the data area.
+    fn run_offset(&self, run: &[u32]) -> Result<u64> {
+        if let Some(&last) = run.last() {
+            self.cluster_offset(last)?;
+        }
+        self.cluster_offset(run[0])
+    }
+
+    /// Fill `buf` with the consecutive clusters of `run`, with a single seek and read
+    fn read_cluster_run(&self, run: &[u32], buf: &mut [u8]) -> Result<()> {
+        self.inner_read_into(self.run_offset(run)?, buf)
+    }
+
+    /// Read a file's chain, trimmed to its size. Empty files don't own any clusters.
+    fn read_file(&mut self, start: u32, size: u32) -> Result<Vec<u8>> {
+        if size == 0 {
+            return Ok(Vec::with_capacity(0));
+        }
+        let warnings = self.warnings.len();
+        let clusters = self.entry_chain(start)?;
+        self.check_chain_size("VFF::read_file", start, size, clusters.len(), warnings)?;
+        let mut raw = self.read_clusters(&clusters)?;
+        raw.truncate(size as usize);
+        Ok(raw)
+    }
+
+    /// Fail, or warn with `SizeMismatch` if lenient, when the `clusters` long chain from
+    /// `start` is too short for a `size` byte file. There were `warnings` warnings before the
+    /// chain was followed, any more mean it was cut short and has been warned about already.
+    fn check_chain_size(
+        &mut self,
+        context: &str,
+        start: u32,
+        size: u32,
+        clusters: usize,
+        warnings: usize,
+    ) -> Result<()> {
+        let len = clusters as u64 * self.header.cluster_size as u64;
+        if len >= size as u64 || self.warnings.len() != warnings {
+            return Ok(());
+        }
+        let warning = Warning::SizeMismatch {
+            start,
+            size,
+            clusters: clusters as u32,
+        };
+        if !self.lenient {
+            return Err(VFFError::InvalidData {
+                context: context.to_owned(),
+                expected: format!("A chain with at least {size:#x} bytes"),
+                found: warning.to_string(),
+                offset: None,
+            });
+        }
+        self.warnings.push(warning);
+        Ok(())
+    }
+
+    /// Read the chain behind a directory entry, leniently if `set_lenient` was used. A start
+    /// cluster of 0, as in the `..` entry of a directory in the root, means the root directory.
+    fn read_entry_chain(&mut self, start: u32) -> Result<Vec<u8>> {
+        if start == 0 {
+            return self.read_root();
+        }
+        let clusters = self.entry_chain(start)?;
+        self.read_clusters(&clusters)
+    }
+
+    fn entry_chain(&mut self, start: u32) -> Result<Arc<[u32]>> {
+        match self.lenient {
+            true => self.get_chain_lenient(start),
+            false => self.get_chain(start),
+        }
+    }
+
+    /// Like `read_chain`, but a chain that runs into a bad cluster or an invalid link is cut
+    /// short instead of failing. The problem is recorded in `warnings`.
+    pub fn read_chain_lenient(&mut self, start: u32) -> Result<Vec<u8>> {
+        let clusters = self.get_chain_lenient(start)?;
+        self.read_clusters(&clusters)
+    }
+
+    /// Only intact chains are cached, so a broken one warns every time it's read
+    fn get_chain_lenient(&mut self, start: u32) -> Result<Arc<[u32]>> {
+        if let Some(chain) = self.chain_cache.get(&start) {
+            return Ok(chain.clone());
+        }
+        let (chain, warning) = self.fat()?.get_chain_lenient(start);
+        let chain: Arc<[u32]> = chain.into();
+        match warning {
+            Some(warning) => self.warnings.push(warning),
+            None => {
+                self.chain_cache.insert(start, chain.clone());
+            }
+        }
+        Ok(chain)
+    }
+
+    pub fn read_chain(&mut self, start: u32) -> Result<Vec<u8>> {
+        let clusters = self.get_chain(start)?;
+        self.read_clusters(&clusters)
+    }
+
+    /// Walk a chain in the primary FAT, reusing the result of any previous walk from `start`
+    fn get_chain(&mut self, start: u32) -> Result<Arc<[u32]>> {
+        if let Some(chain) = self.chain_cache.get(&start) {
+            return Ok(chain.clone());
+        }
+        let chain: Arc<[u32]> = self.fat()?.get_chain(start)?.into();
+        self.chain_cache.insert(start, chain.clone());
+        Ok(chain)
+    }
+
+    /// `get_chain` for callers that only have `&self`, a chain that isn't cached yet is walked
+    /// without being added to the cache
+    fn peek_chain(&self, start: u32) -> Result<Arc<[u32]>> {
+        match self.chain_cache.get(&start) {
+            Some(chain) => Ok(chain.clone()),
+            None => Ok(self.fat()?.get_chain(start)?.into()),
+        }
+    }
+
+    /// How many clusters the chain from `start` is made of, without reading any of them
+    pub fn chain_len(&self, start: u32) -> Result<usize> {
+        if start == 0 {
+            return Ok(0);
+        }
+        Ok(self.peek_chain(start)?.len())
+    }
+
+    /// The space allocated to the chain from `start`, `chain_len` whole clusters
+    pub fn allocated_size(&self, start: u32) -> Result<u64> {
+        Ok(self.chain_len(start)? as u64 * self.header.cluster_size as u64)
+    }
+
+    /// The slack of a file: whatever is left in its last cluster after the end of its `size`
+    /// bytes. FAT doesn't clear it, so it can hold data from an earlier file.
+    pub fn read_slack(&self, start: u32, size: u32) -> Result<Vec<u8>> {
+        let cluster_size = self.header.cluster_size as u32;
+        if size == 0 || size.is_multiple_of(cluster_size) {
+            return Ok(Vec::new());
+        }
+        let chain = self.peek_chain(start)?;
+        let index = (size / cluster_size) as usize;
+        let Some(&last) = chain.get(index) else {
+            return Err(VFFError::InvalidData {
+                context: "Reading file slack".to_owned(),
+                expected: format!("At least {} clusters in the chain", index + 1),
+                found: format!("{} clusters", chain.len()),
+                offset: None,
+            });
+        };
+        let mut cluster = self.read_cluster(last)?;
+        Ok(cluster.split_off((size % cluster_size) as usize))
+    }
+
+    /// Read `len` bytes from `offset` into the file whose chain starts at `start` and which is
+    /// `size` bytes long. Only the clusters the range covers are read, so this is a cheap way
+    /// to look at part of a big file. The range has to be within the file.
+    pub fn read_file_range(
+        &self,
+        start: u32,
+        size: u32,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let end = offset
+            .checked_add(len as u64)
+            .filter(|&end| end <= size as u64);
+        let Some(end) = end else {
+            return Err(VFFError::InvalidData {
+                context: "Reading part of a file".to_owned(),
+                expected: format!("A range within the file's {size:#x} bytes"),
+                found: format!("{len:#x} bytes from {offset:#x}"),
+                offset: None,
+            });
+        };
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let cluster_size = self.header.cluster_size as u64;
+        let chain = self.peek_chain(start)?;
+        let first = (offset / cluster_size) as usize;
+        let last = ((end - 1) / cluster_size) as usize;
+        let Some(clusters) = chain.get(first..=last) else {
+            return Err(VFFError::InvalidData {
+                context: "Reading part of a file".to_owned(),
+                expected: format!("At least {} clusters in the chain", last + 1),
+                found: format!("{} clusters", chain.len()),
+                offset: None,
+            });
+        };
+        let mut ret = Vec::with_capacity(len);
+        for (i, &cluster) in clusters.iter().enumerate() {
+            let cluster_start = (first + i) as u64 * cluster_size;
+            let from = offset.max(cluster_start) - cluster_start;
+            let to = end.min(cluster_start + cluster_size) - cluster_start;
+            ret.extend(
+                self.inner_read(self.cluster_offset(cluster)? + from, (to - from) as usize)?,
+            );
+        }
+        Ok(ret)
+    }
+
+    /// How many runs of contiguous clusters the chain from `start` is split into. A contiguous
+    /// file is a single run, an empty file (start cluster 0) has none.
+    pub fn fragmentation(&self, start: u32) -> Result<usize> {
+        if start == 0 {
+            return Ok(0);
+        }
+        Ok(cluster_runs(&self.peek_chain(start)?).count())
+    }
+
+    /// Like `read_chain`, but if the chain can't be followed in the primary FAT, retry using the
+    /// backup copy. Returns which copy the data was read through.
+    pub fn read_chain_with_fallback(&mut self, start: u32) -> Result<(Vec<u8>, FatCopy)> {
+        match self.get_chain(start) {
+            Ok(clusters) => Ok((self.read_clusters(&clusters)?, FatCopy::Primary)),
+            Err(primary_err) => match self.fats()?.backup.get_chain(start) {
+                Ok(clusters) => Ok((self.read_clusters(&clusters)?, FatCopy::Backup)),
+                // The primary error is the more useful one to report
+                Err(_) => Err(primary_err),
+            },
+        }
+    }
+
+    /// Find the entry at `path` by reading the directories leading to it
+    fn locate(&mut self, path: &str) -> Result<Located> {
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let Some((last, parents)) = components.split_last() else {
+            return Err(VFFError::Other(
+                "The root directory doesn't have an entry".to_owned(),
+            ));
+        };
+        let options = LookupOptions::default();
+        let encoding = self.name_encoding;
+        let find = |data: &[u8], name: &str| -> Result<(Range<usize>, ParsedFATEntry)> {
+            parse_directory_slots(data, false)?
+                .into_iter()
+                .find(|(_, entry)| options.matches(entry, name, encoding))
+                .ok_or_else(|| VFFError::Other(format!("{path} not found")))
+        };
+        let mut dir = DirLocation::Root;
+        let mut data = self.read_root()?;
+        for component in parents {
+            let (_, entry) = find(&data, component)?;
+            if entry.attr & DirectoryFlags::A_DIR == 0 {
+                return Err(VFFError::Other(format!("{component} in {path} is a file")));
+            }
+            if entry.start == 0 {
+                // `..` leading back to the root
+                data = self.read_root()?;
+                dir = DirLocation::Root;
+            } else {
+                let chain = self.get_chain(entry.start.into())?;
+                data = self.read_clusters(&chain)?;
+                dir = DirLocation::Chain(chain);
+            }
+        }
+        let (slots, entry) = find(&data, last)?;
+        Ok(Located { entry, slots, dir })
+    }
+
+    /// Where the 32 byte `slot` of a directory is in the input
+    fn slot_offset(&self, dir: &DirLocation, slot: usize) -> Result<u64> {
+        let offset = slot as u64 * 32;
+        Ok(match dir {
+            DirLocation::Root => self.data_offset - self.root_size + offset,
+            DirLocation::Chain(chain) => {
+                let cluster_size = self.header.cluster_size as u64;
+                self.cluster_offset(chain[(offset / cluster_size) as usize])?
+                    + offset % cluster_size
+            }
+        })
+    }
+
+    /// Set the entry for `cluster` in both FAT copies, in memory and in the input
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<()> {
+        let mut offsets = Vec::with_capacity(2);
+        let fats = self.fats_mut()?;
+        for fat in [&mut fats.primary, &mut fats.backup] {
+            fat.set_cluster(cluster, value)?;
+            offsets.extend(fat.entry_offset(cluster));
+        }
+        for offset in offsets {
+            self.inner_write(offset, &(value as u16).to_le_bytes())?;
+        }
+        self.chain_cache.clear();
+        Ok(())
+    }
+
+    /// Allocate a new chain of `clusters_needed` clusters, see `FAT::allocate_chain`. Both FAT
+    /// copies are updated.
+    pub fn allocate_chain(&mut self, clusters_needed: usize) -> Result<Vec<u32>> {
+        // Fail before touching anything if the VFF is read only
+        self.fd.borrow_mut().writer()?;
+        let chain = self.fats_mut()?.primary.allocate_chain(clusters_needed)?;
+        self.write_new_chain(&chain)?;
+        Ok(chain)
+    }
+
+    /// Allocate a new chain of `clusters_needed` contiguous clusters, see
+    /// `FAT::allocate_contiguous`. Both FAT copies are updated.
+    pub fn allocate_contiguous(&mut self, clusters_needed: usize) -> Result<Vec<u32>> {
+        self.fd.borrow_mut().writer()?;
+        let chain = self
+            .fats_mut()?
+            .primary
+            .allocate_contiguous(clusters_needed)?;
+        self.write_new_chain(&chain)?;
+        Ok(chain)
+    }
+
+    /// Copy the links of a chain just allocated in the primary FAT to the backup and the input
+    fn write_new_chain(&mut self, chain: &[u32]) -> Result<()> {
+        for &cluster in chain {
+            let next = self.fat()?.get_cluster(cluster)?;
+            self.set_fat_entry(cluster, next)?;
+        }
+        Ok(())
+    }
+
+    /// Overwrite the start of data cluster `cluster_num` with `data`, which can't be longer
+    /// than a cluster. The rest of the cluster is left as it was.
+    pub fn write_cluster(&self, cluster_num: u32, data: &[u8]) -> Result<()> {
+        if data.len() > self.header.cluster_size as usize {
+            return Err(VFFError::Other(format!(
+                "{:#x} bytes don't fit in a {:#x} byte cluster",
+                data.len(),
+                self.header.cluster_size
+            )));
+        }
+        self.inner_write(self.cluster_offset(cluster_num)?, data)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.fd.borrow_mut().writer()?.flush()?;
+        Ok(())
+    }
+
+    /// Replace the contents of the file at `path` with `data`, setting its archive attribute.
+    ///
+    /// The file keeps the clusters it has, allocating more if `data` needs them and freeing any
+    /// it no longer needs. The VFF must have been opened with `VffOptions::open_writable`, and a
+    /// `Directory` read before the write won't see it.
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        let located = self.locate(path)?;
+        if located.entry.attr & DirectoryFlags::A_DIR != 0 {
+            return Err(VFFError::Other(format!("{path} is a directory")));
+        }
+        let mut start: u32 = located.entry.start.into();
+        let mut chain: Vec<u32> = match start {
+            0 => Vec::new(),
+            _ => self.get_chain(start)?.to_vec(),
+        };
+        let cluster_size = self.header.cluster_size as usize;
+        let needed = data.len().div_ceil(cluster_size);
+        if data.len() > u32::MAX as usize {
+            return Err(VFFError::Other(format!(
+                "{:#x} bytes is too big for a file",
+                data.len()
+            )));
+        }
+        if needed > chain.len() {
+            let extra = self.allocate_chain(needed - chain.len())?;
+            match chain.last() {
+                Some(&last) => self.set_fat_entry(last, extra[0])?,
+                None => start = extra[0],
+            }
+            chain.extend(extra);
+        }
+        for (&cluster, chunk) in chain.iter().zip(data.chunks(cluster_size)) {
+            self.write_cluster(cluster, chunk)?;
+        }
+        // Free the clusters past the new end of the file
+        if needed < chain.len() {
+            if let Some(last) = needed.checked_sub(1) {
+                self.set_fat_entry(chain[last], 0xffff)?;
+            }
+            for &cluster in &chain[needed..] {
+                self.set_fat_entry(cluster, 0)?;
+            }
+        }
+        let start = if needed == 0 { 0 } else { start };
+        let entry_offset = self.slot_offset(&located.dir, located.slots.end - 1)?;
+        // Mark the file as changed since it was last backed up, like any other FAT driver
+        let attr = located.entry.attr | DirectoryFlags::A_A.bits();
+        self.inner_write(entry_offset + 11, &[attr])?;
+        self.inner_write(entry_offset + 26, &(start as u16).to_le_bytes())?;
+        self.inner_write(entry_offset + 28, &(data.len() as u32).to_le_bytes())?;
+        self.flush()
+    }
+
+    /// Move the file at `path` onto a single run of free clusters, so it can be read in one go.
+    /// Returns how many runs the file was split into before. Files that are already contiguous
+    /// or empty are left alone.
+    ///
+    /// The new clusters are allocated, written and linked in before the old ones are freed, so
+    /// there has to be a free run as long as the whole file.
+    pub fn defragment(&mut self, path: &str) -> Result<usize> {
+        self.fd.borrow_mut().writer()?;
+        let located = self.locate(path)?;
+        if located.entry.attr & DirectoryFlags::A_DIR != 0 {
+            return Err(VFFError::Other(format!("{path} is a directory")));
+        }
+        let start: u32 = located.entry.start.into();
+        if start == 0 {
+            return Ok(0);
+        }
+        let old = self.get_chain(start)?.to_vec();
+        let runs = cluster_runs(&old).count();
+        if runs == 1 {
+            return Ok(runs);
+        }
+        let data = self.read_clusters(&old)?;
+        let new = self.allocate_contiguous(old.len())?;
+        let cluster_size = self.header.cluster_size as usize;
+        for (&cluster, chunk) in new.iter().zip(data.chunks(cluster_size)) {
+            self.write_cluster(cluster, chunk)?;
+        }
+        let entry_offset = self.slot_offset(&located.dir, located.slots.end - 1)?;
+        self.inner_write(entry_offset + 26, &(new[0] as u16).to_le_bytes())?;
+        for cluster in old {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        self.flush()?;
+        Ok(runs)
+    }
+
+    /// Delete the file at `path`, marking its directory entry and any long name slots as
+    /// deleted and freeing its clusters. The data is left in place, so the file can still be
+    /// found with `show_deleted`. Directories can't be deleted.
+    pub fn delete(&mut self, path: &str) -> Result<()> {
+        self.fd.borrow_mut().writer()?;
+        let located = self.locate(path)?;
+        if located.entry.attr & DirectoryFlags::A_DIR != 0 {
+            return Err(VFFError::Other(format!("{path} is a directory")));
+        }
+        // Follow the chain before changing anything, so a broken one leaves the file as it was
+        let start: u32 = located.entry.start.into();
+        let chain = match start {
+            0 => Vec::new(),
+            _ => self.get_chain(start)?.to_vec(),
+        };
+        for slot in located.slots {
+            let offset = self.slot_offset(&located.dir, slot)?;
+            self.inner_write(offset, &[0xe5])?;
+        }
+        for cluster in chain {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        self.flush()
+    }
+
+    /// The root directory sits just before the data area
+    fn read_root(&self) -> Result<Vec<u8>> {
+        self.inner_read(self.data_offset - self.root_size, self.root_size as usize)
+    }
+
+    /// The FAT volume label, stored as a special entry in the root directory
+    pub fn volume_label(&self) -> Result<Option<String>> {
+        for chunk in self.read_root()?.chunks_exact(32) {
+            let mut chunk = <[u8; 32]>::try_from(chunk).unwrap(); // Won't panic because we got our slice from chunks_exact
+            let entry = ParsedFATEntry::from_slice(&mut chunk)?;
+            if matches!(entry.name[0], 0x0 | 0xe5) || !entry.is_volume_label() {
+                continue;
+            }
+            let mut label = entry.name_bytes().to_vec();
+            label.extend_from_slice(&entry.ext);
+            return Ok(Some(self.name_encoding.decode(&label)));
+        }
+        Ok(None)
+    }
+
+    /// Walk every live entry in the tree without going through `Directory`, calling `visitor`
+    /// with each entry and the path of its parent. Broken directory chains are read as far as
+    /// they go, and a directory that loops back to one already visited is not entered again.
+    /// Deleted entries are also visited if `include_deleted` is set, but deleted directories
+    /// aren't entered.
+    fn walk_raw(
+        &self,
+        include_deleted: bool,
+        visitor: &mut dyn FnMut(&str, &ParsedFATEntry),
+    ) -> Result<()> {
+        let root = self.read_root()?;
+        let mut pending = vec![(String::new(), root)];
+        let mut seen_dirs: Vec<u32> = Vec::new();
+        while let Some((path, data)) = pending.pop() {
+            for entry in parse_directory(&data, include_deleted)? {
+                if entry.is_dot_or_dotdot() {
+                    continue;
+                }
+                let name = entry.full_name_in(self.name_encoding);
+                visitor(&path, &entry);
+                let start = entry.start.into();
+                let is_dir = entry.attr & DirectoryFlags::A_DIR != 0;
+                if is_dir && !entry.deleted && !seen_dirs.contains(&start) {
+                    seen_dirs.push(start);
+                    let (chain, _) = self.fat()?.get_chain_lenient(start);
+                    pending.push((path.clone() + "/" + &name, self.read_clusters(&chain)?));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The path and recorded size of every deleted file, without reading any of them. Deleted
+    /// directories aren't entered, see `Directory::recover_deleted`.
+    pub fn deleted_summary(&self) -> Result<Vec<(String, u32)>> {
+        let encoding = self.name_encoding;
+        let mut deleted = Vec::new();
+        self.walk_raw(true, &mut |parent, entry| {
+            if entry.deleted && entry.attr & DirectoryFlags::A_DIR == 0 {
+                deleted.push((
+                    parent.to_owned() + "/" + &entry.full_name_in(encoding),
+                    entry.size,
+                ));
+            }
+        })?;
+        Ok(deleted)
+    }
+
+    /// The file with the latest modification time, and that time. Files without one are
+    /// skipped, and the first file found wins a tie.
+    pub fn newest_file(&self) -> Result<Option<(String, FatDateTime)>> {
+        let encoding = self.name_encoding;
+        let mut newest: Option<(String, FatDateTime)> = None;
+        self.walk_raw(false, &mut |parent, entry| {
+            if entry.attr & (DirectoryFlags::A_DIR | DirectoryFlags::A_VL) != 0 {
+                return;
+            }
+            let Some(modified) = FatDateTime::from_raw(entry.mdate, entry.mtime) else {
+                return;
+            };
+            if newest.as_ref().is_none_or(|(_, time)| modified > *time) {
+                newest = Some((
+                    parent.to_owned() + "/" + &entry.full_name_in(encoding),
+                    modified,
+                ));
+            }
+        })?;
+        Ok(newest)
+    }
+
+    /// Guess the contents of a deleted file, see `Directory::recover_deleted`
+    fn recover_chain(&self, start: u32, size: u32) -> Result<Vec<u8>> {
+        let fat = self.fat()?;
+        let in_range = |cluster: &u32| (2..fat.cluster_count).contains(cluster);
+        if size == 0 || !in_range(&start) {
+            return Ok(Vec::new());
+        }
+        let needed = size.div_ceil(self.header.cluster_size as u32) as usize;
+        let (mut chain, _) = fat.get_chain_lenient(start);
+        if let Some(end) = chain.iter().position(|c| !in_range(c)) {
+            chain.truncate(end);
+        }
+        if chain.is_empty() {
+            chain.push(start);
+        }
+        while chain.len() < needed {
+            let next = chain[chain.len() - 1] + 1;
+            if !in_range(&next) || !fat.get_cluster(next).is_ok_and(|x| fat.is_available(x)) {
+                break;
+            }
+            chain.push(next);
+        }
+        chain.truncate(needed);
+        let mut data = self.read_clusters(&chain)?;
+        data.truncate(size as usize);
+        Ok(data)
+    }
+
+    /// Find chains of clusters that are allocated in the FAT but not reachable from any file or
+    /// directory, such as the remains of deleted files. Each chain is returned in link order.
+    pub fn find_orphans(&self) -> Result<Vec<Vec<u32>>> {
+        let fat = self.fat()?;
+        let referenced = self.referenced_clusters(fat)?;
+        let allocated = |cluster: u32| {
+            fat.get_cluster(cluster)
+                .is_ok_and(|next| fat.is_used(next) || fat.is_last(next))
+        };
+        let orphans: Vec<u32> = (2..fat.cluster_count)
+            .filter(|&c| !referenced[c as usize] && allocated(c))
+            .collect();
+        // A chain starts at an orphan that no other orphan links to
+        let mut linked_to = vec![false; fat.cluster_count as usize];
+        for &cluster in &orphans {
+            if let Some(l) = linked_to.get_mut(fat.get_cluster(cluster)? as usize) {
+                *l = true;
+            }
+        }
+        let mut claimed = referenced;
+        let mut chains = Vec::new();
+        // Heads first, then whatever is left over, which can only be loops
+        let heads = orphans.iter().filter(|&&c| !linked_to[c as usize]);
+        for &head in heads.chain(orphans.iter()) {
+            let mut chain = Vec::new();
+            let mut current = head;
+            while (2..fat.cluster_count).contains(&current)
+                && allocated(current)
+                && !claimed[current as usize]
+            {
+                claimed[current as usize] = true;
+                chain.push(current);
+                current = fat.get_cluster(current)?;
+            }
+            if !chain.is_empty() {
+                chains.push(chain);
+            }
+        }
+        Ok(chains)
+    }
+
+    /// How many clusters are allocated in the FAT, either linking to another cluster or ending a
+    /// chain, without belonging to any file or directory reachable from the root. Bad clusters
+    /// aren't counted. Anything but 0 means lost space or a damaged directory tree, see
+    /// `find_orphans` for where the clusters are.
+    pub fn unreferenced_used_clusters(&self) -> Result<u32> {
+        let fat = self.fat()?;
+        let referenced = self.referenced_clusters(fat)?;
+        let mut count = 0;
+        for cluster in 2..fat.cluster_count {
+            let next = fat.get_cluster(cluster)?;
+            if !referenced[cluster as usize] && (fat.is_used(next) || fat.is_last(next)) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Mark every cluster in the chain of a live file or directory, indexed by cluster number
+    fn referenced_clusters(&self, fat: &FAT) -> Result<Vec<bool>> {
+        let mut referenced = vec![false; fat.cluster_count as usize];
+        self.walk_raw(false, &mut |_, entry| {
+            let (chain, _) = fat.get_chain_lenient(entry.start.into());
+            for cluster in chain {
+                if let Some(r) = referenced.get_mut(cluster as usize) {
+                    *r = true;
+                }
+            }
+        })?;
+        Ok(referenced)
+    }
+
+    /// Walk every file and directory, reporting chains that can't be followed to the end and
+    /// files whose size doesn't match how many clusters they own
+    pub fn check_chains(&self) -> Result<Vec<(String, Warning)>> {
+        let encoding = self.name_encoding;
+        let fat = self.fat()?;
+        let cluster_size = self.header.cluster_size as u64;
+        let mut problems = Vec::new();
+        self.walk_raw(false, &mut |parent, entry| {
+            let path = parent.to_owned() + "/" + &entry.full_name_in(encoding);
+            let start = entry.start.into();
+            let is_dir = entry.attr & DirectoryFlags::A_DIR != 0;
+            // Empty files don't own any clusters
+            if !is_dir && entry.size == 0 && start == 0 {
+                return;
+            }
+            match fat.get_chain_lenient(start) {
+                (_, Some(warning)) => problems.push((path, warning)),
+                (chain, None) if !is_dir => {
+                    let needed = (entry.size as u64).div_ceil(cluster_size);
+                    if chain.len() as u64 != needed {
+                        let warning = Warning::SizeMismatch {
+                            start,
+                            size: entry.size,
+                            clusters: chain.len() as u32,
+                        };
+                        problems.push((path, warning));
+                    }
+                }
+                _ => {}
+            }
+        })?;
+        Ok(problems)
+    }
+
+    /// Find clusters that belong to more than one file or directory. Each is reported with the
+    /// path that claimed it first, then the path that claimed it again.
+    pub fn find_crosslinks(&self) -> Result<Vec<(String, String, u32)>> {
+        let encoding = self.name_encoding;
+        let fat = self.fat()?;
+        let mut paths: Vec<String> = Vec::new();
+        let mut owner: Vec<Option<usize>> = vec![None; fat.cluster_count as usize];
+        let mut crosslinks = Vec::new();
+        self.walk_raw(false, &mut |parent, entry| {
+            let path = parent.to_owned() + "/" + &entry.full_name_in(encoding);
+            let (chain, _) = fat.get_chain_lenient(entry.start.into());
+            for cluster in chain {
+                match owner.get_mut(cluster as usize) {
+                    Some(Some(first)) => {
+                        crosslinks.push((paths[*first].clone(), path.clone(), cluster))
+                    }
+                    Some(slot) => *slot = Some(paths.len()),
+                    None => {}
+                }
+            }
+            paths.push(path);
+        })?;
+        Ok(crosslinks)
+    }
+
+    /// Resolve the chain of a `size` byte file into the byte ranges it occupies in the
+    /// underlying reader. A chain too short for the file is handled as in `read_file`.
+    #[cfg(feature = "rayon")]
+    fn file_extents(&mut self, start: u32, size: u32) -> Result<Extents> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let cluster_size = self.header.cluster_size as usize;
+        let warnings = self.warnings.len();
+        let clusters = self.entry_chain(start)?;
+        self.check_chain_size("VFF::file_extents", start, size, clusters.len(), warnings)?;
+        cluster_runs(&clusters)
+            .map(|run| Ok((self.run_offset(run)?, run.len() * cluster_size)))
+            .collect()
+    }
+
+    /// Read `clusters` in order. The result is allocated once up front and each contiguous run
+    /// is read straight into its part of it.
+    fn read_clusters(&self, clusters: &[u32]) -> Result<Vec<u8>> {
+        let cluster_size = self.header.cluster_size as usize;
+        let len =
+            clusters
+                .len()
+                .checked_mul(cluster_size)
+                .ok_or_else(|| VFFError::InvalidData {
+                    context: "Reading a chain of clusters".to_owned(),
+                    expected: "A chain that fits in memory".to_owned(),
+                    found: format!("{} clusters", clusters.len()),
+                    offset: None,
+                })?;
+        let mut ret = vec![0u8; len];
+        let mut rest = ret.as_mut_slice();
+        for run in cluster_runs(clusters) {
+            let (part, after) = rest.split_at_mut(run.len() * cluster_size);
+            self.read_cluster_run(run, part)?;
+            rest = after;
+        }
+        Ok(ret)
+    }
+}
+
+/// `(offset, length)` byte ranges of the underlying reader
+#[cfg(feature = "rayon")]
+type Extents = Vec<(u64, usize)>;
+
+/// Lowercase hex, as printed by `sha256sum`
+#[cfg(feature = "sha2")]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Split a chain into runs of clusters that are contiguous on disk
+fn cluster_runs(chain: &[u32]) -> impl Iterator<Item = &[u32]> {
+    chain.chunk_by(|a, b| a + 1 == *b)
 }
@@ -1,6 +1,14 @@
-use clap::{Parser, Subcommand};
-use std::{fs::File, path::PathBuf};
-use wiivff::{Result, VFF};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::{
+    cell::RefCell,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+use wiivff::{
+    ClusterState, DiffEntry, Directory, DirectoryContent, DirectoryEntry, DirectoryFlags,
+    FatDateTime, Glob, LsEntry, NameStyle, Result, VFFError, VffOptions, VFF,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -16,33 +24,713 @@ struct Args {
 enum Commands {
     /// List the contents of the VFF
     List {
+        /// The path to the input file (cdb.vff), or - to read the whole image into memory from
+        /// stdin
+        src: PathBuf,
+        #[cfg(feature = "serde")]
+        #[arg(long)]
+        /// Print the listing as a JSON tree
+        json: bool,
+        #[cfg(feature = "sha2")]
+        #[arg(long)]
+        #[cfg_attr(feature = "serde", arg(conflicts_with = "json"))]
+        /// Print the SHA-256 of each file
+        hashes: bool,
+        #[arg(long)]
+        #[cfg_attr(feature = "serde", arg(conflicts_with = "json"))]
+        #[cfg_attr(feature = "sha2", arg(conflicts_with = "hashes"))]
+        /// Print how many contiguous runs of clusters each file is split into
+        fragmentation: bool,
+        #[arg(long)]
+        #[cfg_attr(feature = "serde", arg(conflicts_with = "json"))]
+        #[cfg_attr(feature = "sha2", arg(conflicts_with = "hashes"))]
+        #[arg(conflicts_with = "fragmentation")]
+        /// Print the first cluster of each entry
+        show_clusters: bool,
+        #[arg(long)]
+        #[cfg_attr(feature = "serde", arg(conflicts_with = "json"))]
+        /// Only list files whose path matches this glob
+        filter: Option<String>,
+        #[arg(long)]
+        /// Cut broken chains short with a warning instead of stopping
+        lenient: bool,
+        #[cfg(feature = "encoding_rs")]
+        #[arg(long)]
+        /// Decode 8.3 names as Shift JIS instead of UTF-8
+        shift_jis: bool,
+    },
+    /// Print the contents of the VFF as an indented tree
+    Tree {
         /// The path to the input file (cdb.vff)
         src: PathBuf,
+        #[arg(long)]
+        /// Only show files whose path matches this glob
+        filter: Option<String>,
     },
     /// Dump the VFF to disk
     Dump {
-        /// The path to the input file (cdb.vff)
+        /// The path to the input file (cdb.vff), or - to read the whole image into memory from
+        /// stdin
         src: PathBuf,
         /// Path to dump to
         dest: PathBuf,
+        #[cfg(feature = "rayon")]
+        #[arg(long)]
+        /// Extract files in parallel
+        parallel: bool,
+        #[cfg(feature = "sha2")]
+        #[arg(long)]
+        /// Also write a sha256sum manifest of the extracted files here
+        hash_manifest: Option<PathBuf>,
+        #[arg(long)]
+        #[cfg_attr(feature = "rayon", arg(conflicts_with = "parallel"))]
+        /// Only extract files whose path matches this glob
+        filter: Option<String>,
+        #[arg(long, value_enum, default_value_t = NameStyleArg::Long)]
+        #[cfg_attr(feature = "rayon", arg(conflicts_with = "parallel"))]
+        /// Which names to write files under. `both` uses the long names and lists the 8.3
+        /// aliases in <DEST>.aliases
+        name_style: NameStyleArg,
+        #[arg(long)]
+        /// Cut broken chains short with a warning instead of stopping
+        lenient: bool,
+        #[cfg(feature = "encoding_rs")]
+        #[arg(long)]
+        /// Decode 8.3 names as Shift JIS instead of UTF-8
+        shift_jis: bool,
+        #[arg(long)]
+        #[cfg_attr(feature = "rayon", arg(conflicts_with = "parallel"))]
+        /// Report files that can't be read and carry on with the rest
+        skip_errors: bool,
+        #[arg(long)]
+        #[cfg_attr(feature = "rayon", arg(conflicts_with = "parallel"))]
+        /// Write every file straight into <DEST>, named after its path with / replaced by _
+        flatten: bool,
+    },
+    /// Export the VFF to a tar archive
+    #[cfg(feature = "tar")]
+    Tar {
+        /// The path to the input file (cdb.vff)
+        src: PathBuf,
+        /// Path of the tar archive to create
+        dest: PathBuf,
+        #[arg(long)]
+        /// Only archive files whose path matches this glob
+        filter: Option<String>,
+    },
+    /// Export the VFF to a zip archive
+    #[cfg(feature = "zip")]
+    Zip {
+        /// The path to the input file (cdb.vff)
+        src: PathBuf,
+        /// Path of the zip archive to create
+        dest: PathBuf,
+    },
+    /// Try to recover deleted files
+    Recover {
+        /// The path to the input file (cdb.vff)
+        src: PathBuf,
+        /// Path to write the recovered files to
+        dest: PathBuf,
+    },
+    /// Extract a single file from the VFF
+    Extract {
+        /// The path to the input file (cdb.vff)
+        src: PathBuf,
+        /// The path of the file inside the VFF
+        path: String,
+        /// Path to write the file to
+        dest: PathBuf,
+        #[arg(long)]
+        /// Also write the slack after the end of the file in its last cluster here
+        dump_slack: Option<PathBuf>,
+    },
+    /// Print the header and filesystem statistics
+    Info {
+        /// The path to the input file (cdb.vff)
+        src: PathBuf,
+    },
+    /// Check the VFF for corruption
+    Verify {
+        /// The path to the input file (cdb.vff)
+        src: PathBuf,
+    },
+    /// List the files that were added, removed or modified between two VFFs
+    Diff {
+        /// The path to the older VFF
+        old: PathBuf,
+        /// The path to the newer VFF
+        new: PathBuf,
+    },
+    /// Write a single file from the VFF to stdout
+    Cat {
+        /// The path to the input file (cdb.vff)
+        src: PathBuf,
+        /// The path of the file inside the VFF
+        path: String,
+    },
+    /// Print the size, clusters, attributes and timestamps of a single file or directory
+    Stat {
+        /// The path to the input file (cdb.vff)
+        src: PathBuf,
+        /// The path of the file inside the VFF
+        path: String,
+    },
+    /// Write the raw bytes of a data cluster to stdout
+    RawCluster {
+        /// The path to the input file (cdb.vff)
+        src: PathBuf,
+        #[arg(value_parser = parse_number)]
+        /// The cluster number, starting from 2
+        cluster: u32,
+    },
+    /// Print the value of a FAT entry
+    RawFat {
+        /// The path to the input file (cdb.vff)
+        src: PathBuf,
+        #[arg(value_parser = parse_number)]
+        /// The index of the entry, the same as the cluster number
+        index: u32,
+    },
+    /// Create a new, empty VFF
+    Create {
+        /// The path to the new file, which must not exist
+        dest: PathBuf,
+        #[arg(long, value_parser = parse_number, default_value = "0x1400000")]
+        /// The volume size in bytes
+        volume_size: u32,
+        #[arg(long, value_parser = parse_number, default_value = "0x200")]
+        /// The cluster size in bytes, a power of two of at least 16
+        cluster_size: u32,
     },
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum NameStyleArg {
+    Long,
+    Short,
+    Both,
+}
+
+impl From<NameStyleArg> for NameStyle {
+    fn from(arg: NameStyleArg) -> Self {
+        match arg {
+            NameStyleArg::Long => NameStyle::Long,
+            NameStyleArg::Short => NameStyle::Short,
+            NameStyleArg::Both => NameStyle::Both,
+        }
+    }
+}
+
+/// Parse a decimal or 0x prefixed hex number
+fn parse_number(arg: &str) -> std::result::Result<u32, std::num::ParseIntError> {
+    match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => arg.parse(),
+    }
+}
+
+/// Open `src`, or the VFF piped into stdin if it's `-`. Stdin can't seek, so all of it is read
+/// into memory first, which needs as much memory as the image is big.
+fn open_src(options: VffOptions, src: &Path) -> Result<(Rc<RefCell<VFF>>, Directory)> {
+    if src == Path::new("-") {
+        let mut data = Vec::new();
+        io::stdin().lock().read_to_end(&mut data)?;
+        return options.open(io::Cursor::new(data));
+    }
+    options.open(std::fs::File::open(src)?)
+}
+
+fn check_fats(vff: &VFF) -> Result<()> {
+    let mismatched = vff.verify_fats()?;
+    if !mismatched.is_empty() {
+        eprintln!(
+            "Warning: the FAT copies disagree on {} clusters, the image may be corrupt",
+            mismatched.len()
+        );
+    }
+    Ok(())
+}
+
+/// Run every consistency check, printing each problem found. Returns whether the VFF is clean.
+fn verify(vff: &VFF) -> Result<bool> {
+    let mut problems = 0;
+    for cluster in vff.verify_fats()? {
+        let primary = vff.fat()?.get_cluster(cluster)?;
+        let backup = vff.backup_fat()?.get_cluster(cluster)?;
+        println!("FAT copies disagree on cluster {cluster:04x}: {primary:04x} vs {backup:04x}");
+        problems += 1;
+    }
+    for (path, warning) in vff.check_chains()? {
+        println!("{path}: {warning}");
+        problems += 1;
+    }
+    for (first, second, cluster) in vff.find_crosslinks()? {
+        println!("Cluster {cluster:04x} is used by both {first} and {second}");
+        problems += 1;
+    }
+    // Bad clusters are worn out media, not an inconsistency, so they aren't counted
+    for cluster in vff.bad_clusters()? {
+        println!("Cluster {cluster:04x} is marked bad");
+    }
+    let orphans = vff.find_orphans()?;
+    for chain in &orphans {
+        println!(
+            "Chain of {} clusters starting at {:04x} isn't used by any file",
+            chain.len(),
+            chain[0]
+        );
+        problems += 1;
+    }
+    // The same clusters as the chains above, so this is a summary rather than another problem
+    let unreferenced = vff.unreferenced_used_clusters()?;
+    if unreferenced > 0 {
+        println!(
+            "{unreferenced} allocated clusters in {} orphan chains aren't reachable from the root",
+            orphans.len()
+        );
+    }
+    if problems > 0 {
+        println!("{problems} problems found");
+    }
+    Ok(problems == 0)
+}
+
+/// `path [size]` for files, with a ` [DELETED]` marker, and just the path for empty directories.
+/// With `show_clusters`, the start cluster follows as ` @cluster 0x1234`.
+fn ls_line(entry: &LsEntry, show_clusters: bool) -> String {
+    let mut line = entry.path.clone();
+    if let Some(size) = entry.size {
+        line += &format!(" [{size:#06x}]");
+    }
+    if let (true, Some(start)) = (show_clusters, entry.start) {
+        line += &format!(" @cluster {start:#06x}");
+    }
+    if entry.deleted {
+        line += " [DELETED]";
+    }
+    line
+}
+
+fn report_warnings(vff: &VFF) {
+    for warning in vff.warnings() {
+        eprintln!("Warning: {warning}");
+    }
+}
+
+fn read_file(root_dir: &Directory, path: &str) -> Result<Vec<u8>> {
+    let entry = root_dir.get_path(path)?;
+    if let DirectoryContent::NoContent = entry.content() {
+        return Err(VFFError::Other(format!("{path} not found")));
+    }
+    entry.load_bytes()
+}
+
+/// The attributes set in `flags`, spelled out
+fn attribute_names(flags: DirectoryFlags) -> String {
+    let names = [
+        (DirectoryFlags::A_R, "read only"),
+        (DirectoryFlags::A_H, "hidden"),
+        (DirectoryFlags::A_S, "system"),
+        (DirectoryFlags::A_VL, "volume label"),
+        (DirectoryFlags::A_DIR, "directory"),
+        (DirectoryFlags::A_A, "archive"),
+        (DirectoryFlags::A_DEV, "device"),
+    ];
+    let set: Vec<&str> = names
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect();
+    match set.is_empty() {
+        true => "none".to_owned(),
+        false => set.join(", "),
+    }
+}
+
+#[cfg(feature = "encoding_rs")]
+fn name_encoding(shift_jis: bool) -> wiivff::NameEncoding {
+    match shift_jis {
+        true => wiivff::NameEncoding::ShiftJis,
+        false => wiivff::NameEncoding::Utf8Lossy,
+    }
+}
+
 pub fn main() -> Result<()> {
     let args = Args::parse();
 
     match args.cmd {
-        Commands::List { src } => {
-            let file = File::open(src)?;
-            let (_, root_dir) = VFF::new(file)?;
-            for entry in root_dir.ls(args.show_deleted)? {
-                println!("{entry}");
+        Commands::List {
+            src,
+            #[cfg(feature = "serde")]
+            json,
+            #[cfg(feature = "sha2")]
+            hashes,
+            fragmentation,
+            show_clusters,
+            filter,
+            lenient,
+            #[cfg(feature = "encoding_rs")]
+            shift_jis,
+        } => {
+            let options = VffOptions::new().lenient(lenient);
+            #[cfg(feature = "encoding_rs")]
+            let options = options.name_encoding(name_encoding(shift_jis));
+            let (vff, root_dir) = open_src(options, &src)?;
+            check_fats(&vff.borrow())?;
+            #[cfg(feature = "serde")]
+            if json {
+                let listing = root_dir.listing(args.show_deleted)?;
+                let json = serde_json::to_string_pretty(&listing)
+                    .map_err(|e| VFFError::Other(e.to_string()))?;
+                println!("{json}");
+                report_warnings(&vff.borrow());
+                return Ok(());
+            }
+            let glob = filter.map(|filter| Glob::new(&filter));
+            let matches =
+                |entry: &DirectoryEntry| glob.as_ref().is_none_or(|g| g.matches_entry(entry));
+            #[cfg(feature = "sha2")]
+            if hashes {
+                root_dir.visit(args.show_deleted, &mut |entry| {
+                    if !entry.is_directory() && matches(entry) {
+                        let hash: String =
+                            entry.sha256()?.iter().map(|b| format!("{b:02x}")).collect();
+                        let path = entry.path().to_owned() + "/" + entry.full_name();
+                        println!("{path} [{:#06x}] {hash}", entry.size());
+                    }
+                    Ok(())
+                })?;
+                report_warnings(&vff.borrow());
+                return Ok(());
+            }
+            if fragmentation {
+                root_dir.visit(args.show_deleted, &mut |entry| {
+                    if let (false, true, Some(start)) =
+                        (entry.is_directory(), matches(entry), entry.start_cluster())
+                    {
+                        let runs = vff.borrow().fragmentation(start)?;
+                        let path = entry.path().to_owned() + "/" + entry.full_name();
+                        println!("{path} [{:#06x}] {runs}", entry.size());
+                    }
+                    Ok(())
+                })?;
+                report_warnings(&vff.borrow());
+                return Ok(());
             }
+            let listing = match glob {
+                Some(_) => root_dir.ls_entries_matching(args.show_deleted, &matches)?,
+                None => root_dir.ls_entries(args.show_deleted)?,
+            };
+            for entry in listing {
+                println!("{}", ls_line(&entry, show_clusters));
+            }
+            report_warnings(&vff.borrow());
+        }
+        Commands::Tree { src, filter } => {
+            let (vff, root_dir) = VFF::from_path(src)?;
+            check_fats(&vff.borrow())?;
+            let glob = filter.map(|filter| Glob::new(&filter));
+            let matches =
+                |entry: &DirectoryEntry| glob.as_ref().is_none_or(|g| g.matches_entry(entry));
+            for line in root_dir.tree(args.show_deleted, &matches)? {
+                println!("{line}");
+            }
+        }
+        Commands::Dump {
+            src,
+            dest,
+            #[cfg(feature = "rayon")]
+            parallel,
+            #[cfg(feature = "sha2")]
+            hash_manifest,
+            filter,
+            name_style,
+            lenient,
+            #[cfg(feature = "encoding_rs")]
+            shift_jis,
+            skip_errors,
+            flatten,
+        } => {
+            let options = VffOptions::new().lenient(lenient);
+            #[cfg(feature = "encoding_rs")]
+            let options = options.name_encoding(name_encoding(shift_jis));
+            let (vff, root_dir) = open_src(options, &src)?;
+            check_fats(&vff.borrow())?;
+            let glob = filter.map(|filter| Glob::new(&filter));
+            let matches =
+                |entry: &DirectoryEntry| glob.as_ref().is_none_or(|g| g.matches_entry(entry));
+            #[cfg(not(feature = "rayon"))]
+            let parallel = false;
+            let mut failed = Vec::new();
+            if parallel && src == Path::new("-") {
+                // Each thread opens its own handle to the file
+                return Err(VFFError::Other(
+                    "--parallel needs a file to read from, not stdin".to_owned(),
+                ));
+            }
+            if parallel {
+                #[cfg(feature = "rayon")]
+                root_dir.dump_parallel(&src, dest, args.show_deleted)?;
+            } else {
+                let aliases = if flatten {
+                    let report = root_dir.dump_flat(
+                        dest.clone(),
+                        args.show_deleted,
+                        &matches,
+                        name_style.into(),
+                        skip_errors,
+                    )?;
+                    failed = report.failed;
+                    report.aliases
+                } else if skip_errors {
+                    let report = root_dir.dump_named_skipping_errors(
+                        dest.clone(),
+                        args.show_deleted,
+                        &matches,
+                        name_style.into(),
+                    )?;
+                    failed = report.failed;
+                    report.aliases
+                } else {
+                    root_dir.dump_named(
+                        dest.clone(),
+                        args.show_deleted,
+                        &matches,
+                        name_style.into(),
+                    )?
+                };
+                if let NameStyleArg::Both = name_style {
+                    let mut sidecar = dest.into_os_string();
+                    sidecar.push(".aliases");
+                    let mut out = io::BufWriter::new(std::fs::File::create(sidecar)?);
+                    for (path, alias) in aliases {
+                        writeln!(out, "{path}\t{alias}")?;
+                    }
+                    out.flush()?;
+                }
+            }
+            #[cfg(feature = "sha2")]
+            if let Some(manifest) = hash_manifest {
+                let out = io::BufWriter::new(std::fs::File::create(manifest)?);
+                root_dir.write_sha256_manifest(out, args.show_deleted, &matches)?;
+            }
+            report_warnings(&vff.borrow());
+            for (path, e) in &failed {
+                eprintln!("Failed to dump {path}: {e}");
+            }
+            if !failed.is_empty() {
+                eprintln!("{} files failed", failed.len());
+                std::process::exit(1);
+            }
+        }
+        #[cfg(feature = "tar")]
+        Commands::Tar { src, dest, filter } => {
+            let (vff, root_dir) = VFF::from_path(src)?;
+            check_fats(&vff.borrow())?;
+            let glob = filter.map(|filter| Glob::new(&filter));
+            let matches =
+                |entry: &DirectoryEntry| glob.as_ref().is_none_or(|g| g.matches_entry(entry));
+            let out = io::BufWriter::new(std::fs::File::create(dest)?);
+            root_dir.export_tar_matching(out, args.show_deleted, &matches)?;
+        }
+        #[cfg(feature = "zip")]
+        Commands::Zip { src, dest } => {
+            let (vff, root_dir) = VFF::from_path(src)?;
+            check_fats(&vff.borrow())?;
+            let out = io::BufWriter::new(std::fs::File::create(dest)?);
+            root_dir.export_zip(out, args.show_deleted)?;
+        }
+        Commands::Recover { src, dest } => {
+            let (_, root_dir) = VFF::from_path(src)?;
+            root_dir.recover_deleted(dest)?;
+        }
+        Commands::Extract {
+            src,
+            path,
+            dest,
+            dump_slack,
+        } => {
+            let (vff, root_dir) = VFF::from_path(src)?;
+            std::fs::write(dest, read_file(&root_dir, &path)?)?;
+            if let Some(slack_dest) = dump_slack {
+                let entry = root_dir.get_path(&path)?;
+                let start = entry.start_cluster().unwrap_or(0);
+                std::fs::write(slack_dest, vff.borrow().read_slack(start, entry.size())?)?;
+            }
+        }
+        Commands::Info { src } => {
+            let (vff, root_dir) = VFF::from_path(src)?;
+            // Reading the root needs the VFF mutably, so do it before holding a borrow
+            let root_entries = root_dir.entries(args.show_deleted, false)?;
+            let vff = vff.borrow();
+            let header = vff.header();
+            let fat = vff.fat()?;
+            let root_dirs = root_entries.iter().filter(|e| e.is_directory()).count();
+            if let Some(label) = vff.volume_label()? {
+                println!("Volume label:  {label}");
+            }
+            println!("Unknown word:  {:#010x}", header.unknown);
+            println!("Volume size:   {:#x}", header.volume_size);
+            println!("Cluster size:  {:#x}", header.cluster_size);
+            println!("Cluster count: {:#x}", header.cluster_count);
+            let preamble: String = header
+                .raw_preamble2
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            println!("Preamble:      {preamble}");
+            println!("FAT type:      {:?}", fat.fattype());
+            println!("Used clusters: {:#x}", fat.used_cluster_count());
+            println!("Free clusters: {:#x}", fat.free_cluster_count());
+            let bad = fat.bad_clusters();
+            if bad.is_empty() {
+                println!("Bad clusters:  0x0");
+            } else {
+                let list: Vec<String> = bad.iter().map(|c| format!("{c:04x}")).collect();
+                println!("Bad clusters:  {:#x} ({})", bad.len(), list.join(", "));
+            }
+            println!("Free space:    {:#x} bytes", vff.free_space_bytes()?);
+            println!(
+                "Root entries:  {} files, {root_dirs} directories",
+                root_entries.len() - root_dirs
+            );
+            println!(
+                "Root slots:    {} of {} used",
+                vff.root_used()?,
+                vff.root_capacity()
+            );
+            let deleted = vff.deleted_summary()?;
+            println!(
+                "Deleted files: {}, {:#x} bytes",
+                deleted.len(),
+                deleted.iter().map(|(_, size)| *size as u64).sum::<u64>()
+            );
+        }
+        Commands::Verify { src } => {
+            let (vff, _) = VFF::from_path(src)?;
+            if !verify(&vff.borrow())? {
+                std::process::exit(1);
+            }
+            println!("No problems found");
+        }
+        Commands::Diff { old, new } => {
+            let (_, old_root) = VFF::from_path(old)?;
+            let (_, new_root) = VFF::from_path(new)?;
+            let diff = old_root.diff(&new_root)?;
+            let heading = |d: &DiffEntry| match d {
+                DiffEntry::Added(_) => "Added",
+                DiffEntry::Removed(_) => "Removed",
+                DiffEntry::Modified(_) => "Modified",
+            };
+            for group in ["Added", "Removed", "Modified"] {
+                let paths: Vec<&str> = diff
+                    .iter()
+                    .filter(|d| heading(d) == group)
+                    .map(|d| d.path())
+                    .collect();
+                if paths.is_empty() {
+                    continue;
+                }
+                println!("{group}:");
+                for path in paths {
+                    println!("  {path}");
+                }
+            }
+            if diff.is_empty() {
+                println!("No differences");
+            }
+        }
+        Commands::Cat { src, path } => {
+            let (_, root_dir) = VFF::from_path(src)?;
+            let data = read_file(&root_dir, &path)?;
+            // Go through write_all so binary files come out untouched
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&data)?;
+            stdout.flush()?;
+        }
+        Commands::Stat { src, path } => {
+            let (vff, root_dir) = VFF::from_path(src)?;
+            let entry = root_dir.get_path(&path)?;
+            if let DirectoryContent::NoContent = entry.content() {
+                return Err(VFFError::Other(format!("{path} not found")));
+            }
+            let start = entry.start_cluster().unwrap_or(0);
+            let vff = vff.borrow();
+            let clusters = vff.chain_len(start)?;
+            let metadata = entry.metadata();
+            let time = |time: Option<FatDateTime>| time.map_or("-".to_owned(), |t| t.to_string());
+            println!("Path:       {}/{}", entry.path(), entry.full_name());
+            println!("Short name: {}", entry.short_name());
+            println!("Size:       {:#x} bytes", metadata.size);
+            println!(
+                "Allocated:  {:#x} bytes in {clusters} clusters",
+                clusters as u64 * vff.header().cluster_size as u64
+            );
+            println!(
+                "Attributes: {:#04x} ({})",
+                metadata.attributes,
+                attribute_names(metadata.flags())
+            );
+            println!("Start:      {start:#06x}");
+            println!("Created:    {}", time(metadata.created));
+            println!("Modified:   {}", time(metadata.modified));
+            // Only the date of the last access is recorded
+            let accessed = metadata
+                .accessed
+                .map(|t| format!("{:04}-{:02}-{:02}", t.year, t.month, t.day));
+            println!("Accessed:   {}", accessed.as_deref().unwrap_or("-"));
+        }
+        Commands::RawCluster { src, cluster } => {
+            let (vff, _) = VFF::from_path(src)?;
+            let vff = vff.borrow();
+            let count = vff.header().cluster_count;
+            if !(2..count).contains(&cluster) {
+                return Err(VFFError::Other(format!(
+                    "Cluster {cluster:#x} doesn't exist, data clusters go from 0x2 to {:#x}",
+                    count.saturating_sub(1)
+                )));
+            }
+            let data = vff.read_cluster(cluster)?;
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&data)?;
+            stdout.flush()?;
+        }
+        Commands::RawFat { src, index } => {
+            let (vff, _) = VFF::from_path(src)?;
+            let vff = vff.borrow();
+            let map = vff.fat_map()?;
+            let Some(state) = map.get(index as usize) else {
+                return Err(VFFError::Other(format!(
+                    "FAT entry {index:#x} doesn't exist, the FAT goes from 0x0 to {:#x}",
+                    map.len().saturating_sub(1)
+                )));
+            };
+            let meaning = match state {
+                ClusterState::Free => "free",
+                ClusterState::Used => "links to the next cluster",
+                ClusterState::Bad => "bad",
+                ClusterState::Eoc => "end of chain",
+                ClusterState::Reserved => "reserved",
+            };
+            println!("{:#06x} ({meaning})", vff.fat()?.get_cluster(index)?);
         }
-        Commands::Dump { src, dest } => {
-            let file = File::open(src)?;
-            let (_, root_dir) = VFF::new(file)?;
-            root_dir.dump(dest, args.show_deleted)?;
+        Commands::Create {
+            dest,
+            volume_size,
+            cluster_size,
+        } => {
+            let cluster_size = u16::try_from(cluster_size).map_err(|_| {
+                VFFError::Other(format!("A cluster size of {cluster_size:#x} is too big"))
+            })?;
+            let (vff, _) = VFF::create(&dest, volume_size, cluster_size)?;
+            let vff = vff.borrow();
+            println!(
+                "Created {} with {:#x} clusters of {:#x} bytes",
+                dest.display(),
+                vff.header().cluster_count,
+                vff.header().cluster_size
+            );
         }
     }
     Ok(())
@@ -12,6 +12,7 @@ pub fn vff_header() -> Result<()> {
     assert_eq!(header.volume_size, 0x1400000);
     assert_eq!(header.cluster_size, 0x200);
     assert_eq!(header.cluster_count, 0xa000);
+    assert_eq!(vff.borrow().root_dir_size(), 0x1000);
     Ok(())
 }
 
@@ -21,7 +22,10 @@ pub fn ls_root_dir() -> Result<()> {
     let (_, root_dir) = VFF::new(f)?;
     let root_dir_contents = root_dir.ls(false)?;
     assert_eq!(root_dir_contents.len(), 2);
-    assert!(root_dir_contents.contains(&"/CDB~1.CON [0x0004]".to_owned()));
+    // The `~1` alias means there's a long name, which is what gets listed
+    let cdb = root_dir.get_path("CDB~1.CON")?;
+    assert_eq!(cdb.short_name(), "CDB~1.CON");
+    assert!(root_dir_contents.contains(&format!("/{} [0x0004]", cdb.full_name())));
     assert!(root_dir_contents
         .contains(&"/2022/10/15/21/44/HAEA_#1/LOG/2B06C4C3.000 [0x0ca0]".to_owned()));
     Ok(())
@@ -37,9 +41,10 @@ pub fn dump_root() -> Result<()> {
     }
     let (_, root_dir) = VFF::new(f)?;
     root_dir.dump(test_dir.clone(), false)?;
+    // Dumped under its long name, see ls_root_dir
     let file1 = {
         let mut temp = test_dir.clone();
-        temp.push("CDB~1.CON");
+        temp.push(root_dir.get_path("CDB~1.CON")?.full_name());
         temp
     };
     let mut cdb_file: Vec<u8> = Vec::with_capacity(0x4);
@@ -80,3 +85,2919 @@ pub fn check_file_size_vs_header() -> Result<()> {
     assert_eq!(header.volume_size, expected_size);
     Ok(())
 }
+
+const IMG_CLUSTER_SIZE: usize = 0x200;
+const IMG_CLUSTER_COUNT: usize = 0x1000;
+const IMG_FAT_OFFSET: usize = 0x20;
+const IMG_FAT_SIZE: usize = IMG_CLUSTER_COUNT * 2;
+const IMG_ROOT_OFFSET: usize = IMG_FAT_OFFSET + IMG_FAT_SIZE * 2;
+const IMG_DATA_OFFSET: usize = IMG_ROOT_OFFSET + 0x1000;
+
+/// Builds small FAT16 VFF images in memory so tests can exercise layouts the bundled image doesn't have
+struct ImageBuilder {
+    data: Vec<u8>,
+    next_cluster: u16,
+    next_slot: std::collections::HashMap<Option<u16>, usize>,
+}
+
+impl ImageBuilder {
+    fn new() -> Self {
+        let volume_size = (IMG_CLUSTER_SIZE * IMG_CLUSTER_COUNT) as u32;
+        let mut data = vec![0u8; volume_size as usize];
+        data[0..4].copy_from_slice(&EXPECTED_FILE_MAGIC);
+        data[8..12].copy_from_slice(&volume_size.to_be_bytes());
+        data[12..14].copy_from_slice(&((IMG_CLUSTER_SIZE / 16) as u16).to_be_bytes());
+        let mut ret = ImageBuilder {
+            data,
+            next_cluster: 2,
+            next_slot: std::collections::HashMap::new(),
+        };
+        ret.set_fat(0, 0xfff8);
+        ret.set_fat(1, 0xffff);
+        ret
+    }
+
+    fn set_fat(&mut self, cluster: u16, value: u16) {
+        for copy in 0..2 {
+            let offset = IMG_FAT_OFFSET + copy * IMG_FAT_SIZE + cluster as usize * 2;
+            self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn cluster_offset(cluster: u16) -> usize {
+        IMG_DATA_OFFSET + (cluster as usize - 2) * IMG_CLUSTER_SIZE
+    }
+
+    /// Allocate a contiguous chain big enough for `contents` and fill it
+    fn alloc(&mut self, contents: &[u8]) -> u16 {
+        let count = contents.len().div_ceil(IMG_CLUSTER_SIZE).max(1) as u16;
+        let start = self.next_cluster;
+        for cluster in start..start + count {
+            let next = if cluster == start + count - 1 {
+                0xffff
+            } else {
+                cluster + 1
+            };
+            self.set_fat(cluster, next);
+        }
+        self.next_cluster += count;
+        let offset = Self::cluster_offset(start);
+        self.data[offset..offset + contents.len()].copy_from_slice(contents);
+        start
+    }
+
+    fn raw_entry(name: &str, ext: &str, attr: u8, start: u16, size: u32) -> [u8; 32] {
+        let mut entry = [0u8; 32];
+        entry[0..11].fill(b' ');
+        entry[0..name.len()].copy_from_slice(name.as_bytes());
+        entry[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+        entry[11] = attr;
+        entry[26..28].copy_from_slice(&start.to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    /// Append a raw entry to the root directory (`None`) or the directory starting at `parent`
+    fn add_raw(&mut self, parent: Option<u16>, entry: [u8; 32]) {
+        let slot = self.next_slot.entry(parent).or_insert(0);
+        let offset = match parent {
+            Some(cluster) => Self::cluster_offset(cluster),
+            None => IMG_ROOT_OFFSET,
+        } + *slot * 32;
+        *slot += 1;
+        self.data[offset..offset + 32].copy_from_slice(&entry);
+    }
+
+    fn add_file(&mut self, parent: Option<u16>, name: &str, ext: &str, contents: &[u8]) -> u16 {
+        let start = if contents.is_empty() {
+            0
+        } else {
+            self.alloc(contents)
+        };
+        let entry = Self::raw_entry(name, ext, 0x20, start, contents.len() as u32);
+        self.add_raw(parent, entry);
+        start
+    }
+
+    fn add_dir(&mut self, parent: Option<u16>, name: &str) -> u16 {
+        let start = self.alloc(&[]);
+        self.add_raw(parent, Self::raw_entry(name, "", 0x10, start, 0));
+        self.add_raw(Some(start), Self::raw_entry(".", "", 0x10, start, 0));
+        self.add_raw(
+            Some(start),
+            Self::raw_entry("..", "", 0x10, parent.unwrap_or(0), 0),
+        );
+        start
+    }
+
+    fn build(self) -> std::io::Cursor<Vec<u8>> {
+        std::io::Cursor::new(self.data)
+    }
+}
+
+#[test]
+pub fn entry_metadata() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "LOGS");
+    let mut entry = ImageBuilder::raw_entry("PLAY", "LOG", 0x21, img.alloc(b"hello"), 5);
+    // Modified 2022-10-15 21:44:30, created the same day at 21:44:10.5
+    entry[13] = 50;
+    entry[14..16].copy_from_slice(&((21 << 11) | (44 << 5) | 5u16).to_le_bytes());
+    entry[16..18].copy_from_slice(&((42 << 9) | (10 << 5) | 15u16).to_le_bytes());
+    entry[22..24].copy_from_slice(&((21 << 11) | (44 << 5) | 15u16).to_le_bytes());
+    entry[24..26].copy_from_slice(&((42 << 9) | (10 << 5) | 15u16).to_le_bytes());
+    img.add_raw(Some(dir), entry);
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let con = root_dir.get_path("CDB~1.CON")?;
+    assert_eq!(con.size(), 4);
+    assert_eq!(con.file().unwrap().len(), 4);
+
+    let logs = root_dir.get_path("LOGS")?;
+    assert!(logs.is_directory());
+    let file = logs.dir().unwrap().get_path("PLAY.LOG")?;
+    assert!(!file.is_directory());
+    assert_eq!(file.size(), 5);
+    assert_eq!(file.attributes(), 0x21);
+    assert_eq!(file.flags(), DirectoryFlags::A_R | DirectoryFlags::A_A);
+    assert!(file.is_readonly() && file.is_archive());
+    assert!(!file.is_hidden() && !file.is_system() && !file.is_volume_label());
+    let metadata = file.metadata();
+    assert_eq!(
+        metadata.modified.unwrap().to_string(),
+        "2022-10-15 21:44:30"
+    );
+    assert_eq!(metadata.created.unwrap().to_string(), "2022-10-15 21:44:10");
+    assert!(metadata.accessed.is_none());
+    Ok(())
+}
+
+#[test]
+pub fn root_capacity() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_dir(None, "2022");
+    img.add_lfn_file(None, "cdb.config.bin", ("CDBCON~1", "BIN"), &[1, 2, 3]);
+    let mut gone = ImageBuilder::raw_entry("_ONE", "TXT", 0x20, 0, 0);
+    gone[0] = 0xe5;
+    img.add_raw(None, gone);
+    let (vff, _) = VFF::new(img.build())?;
+    let vff = vff.borrow();
+    assert_eq!(vff.root_capacity(), 128);
+    // The directory, then two long name slots and the short entry
+    assert_eq!(vff.root_used()?, 4);
+
+    let (vff, _) = VFF::new_with_root_size(ImageBuilder::new().build(), 0, 0x800)?;
+    assert_eq!(vff.borrow().root_capacity(), 64);
+    assert_eq!(vff.borrow().root_used()?, 0);
+    Ok(())
+}
+
+#[test]
+pub fn list_detailed() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "LOGS");
+    let play_start = img.alloc(b"hello");
+    let mut entry = ImageBuilder::raw_entry("PLAY", "LOG", 0x21, play_start, 5);
+    entry[22..24].copy_from_slice(&((21 << 11) | (44 << 5) | 15u16).to_le_bytes());
+    entry[24..26].copy_from_slice(&((42 << 9) | (10 << 5) | 15u16).to_le_bytes());
+    img.add_raw(Some(dir), entry);
+    let mut gone = ImageBuilder::raw_entry("_ONE", "TXT", 0x20, img.alloc(b"old"), 3);
+    gone[0] = 0xe5;
+    img.add_raw(None, gone);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let info = root_dir.list_detailed(false)?;
+    let paths: Vec<&str> = info.iter().map(|i| i.path.as_str()).collect();
+    assert_eq!(paths, ["/LOGS", "/LOGS/PLAY.LOG"]);
+    assert!(info[0].is_dir && info[0].attributes.contains(DirectoryFlags::A_DIR));
+    let play = &info[1];
+    assert!(!play.is_dir && !play.deleted);
+    assert_eq!(play.size, 5);
+    assert_eq!(play.attributes, DirectoryFlags::A_R | DirectoryFlags::A_A);
+    assert_eq!(play.modified.unwrap().to_string(), "2022-10-15 21:44:30");
+    assert!(play.created.is_none() && play.accessed.is_none());
+    assert_eq!(play.start, play_start as u32);
+    assert_eq!(info[0].start, dir as u32);
+
+    assert_eq!(root_dir.paths(false)?, ["/LOGS/PLAY.LOG"]);
+    assert_eq!(
+        root_dir.paths(true)?,
+        ["/LOGS/PLAY.LOG", "/\u{fffd}ONE.TXT"]
+    );
+    let logs = root_dir.get_path("LOGS")?;
+    assert_eq!(logs.dir().unwrap().paths(false)?, ["/LOGS/PLAY.LOG"]);
+
+    let info = root_dir.list_detailed(true)?;
+    let deleted: Vec<&FileInfo> = info.iter().filter(|i| i.deleted).collect();
+    assert_eq!(deleted.len(), 1);
+    assert_eq!(deleted[0].size, 3);
+    Ok(())
+}
+
+#[test]
+pub fn read_file_by_name() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_file(Some(dir), "PLAY", "LOG", b"hello");
+    img.add_lfn_file(None, "cdb.config.bin", ("CDBCON~1", "BIN"), &[1, 2, 3]);
+    img.add_file(None, "EMPTY", "BIN", &[]);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    assert_eq!(root_dir.read_file("cdb.config.bin")?, [1, 2, 3]);
+    assert_eq!(root_dir.read_file("CDBCON~1.BIN")?, [1, 2, 3]);
+    assert!(root_dir.read_file("EMPTY.BIN")?.is_empty());
+    let year = root_dir.get_path("2022")?;
+    assert_eq!(year.dir().unwrap().read_file("play.log")?, b"hello");
+    // Only names in this directory, not paths
+    assert!(root_dir.read_file("2022/PLAY.LOG").is_err());
+    assert!(root_dir.read_file("2022").is_err());
+    assert!(root_dir.read_file("MISSING.BIN").is_err());
+    Ok(())
+}
+
+#[test]
+pub fn get_path() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let year = img.add_dir(None, "2022");
+    let month = img.add_dir(Some(year), "10");
+    img.add_file(Some(month), "2B06C4C3", "000", b"play log");
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let file = root_dir.get_path("/2022/10/2b06c4c3.000")?;
+    assert_eq!(file.path(), "/2022/10");
+    assert_eq!(file.name(), "2B06C4C3");
+    assert_eq!(file.file().unwrap(), b"play log");
+    assert!(root_dir.get_path("2022//10/")?.dir().is_some());
+    assert!(root_dir.get_path("")?.is_directory());
+    assert!(matches!(
+        root_dir.get_path("/2022/11/2B06C4C3.000")?.content(),
+        DirectoryContent::NoContent
+    ));
+    assert!(root_dir.get_path("/CDB~1.CON/oops").is_err());
+    Ok(())
+}
+
+#[test]
+pub fn directory_entries() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let year = img.add_dir(None, "2022");
+    let month = img.add_dir(Some(year), "10");
+    img.add_file(Some(month), "2B06C4C3", "000", b"play log");
+    img.add_dir(Some(month), "15");
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    let month = root_dir.get_path("2022/10")?;
+    let entries = month.dir().unwrap().entries(false, false)?;
+    assert_eq!(entries.len(), 2);
+    assert!(matches!(entries[0].content(), DirectoryContent::Unloaded));
+    assert_eq!(entries[0].load_bytes()?, b"play log");
+    assert!(entries[1].dir().is_some());
+    assert_eq!(entries[1].dir().unwrap().entries(false, false)?.len(), 0);
+
+    let with_dots = month.dir().unwrap().entries(false, true)?;
+    let names: Vec<&str> = with_dots.iter().map(|e| e.name()).collect();
+    assert_eq!(names, [".", "..", "2B06C4C3", "15"]);
+    let dots: Vec<_> = with_dots
+        .iter()
+        .map(|e| (e.is_dot(), e.is_dotdot()))
+        .collect();
+    assert_eq!(
+        dots,
+        [(true, false), (false, true), (false, false), (false, false)]
+    );
+    // The `..` of a directory in the root has start cluster 0, meaning the root
+    let up = &root_dir
+        .get_path("2022")?
+        .dir()
+        .unwrap()
+        .entries(false, true)?[1];
+    assert!(up.is_dotdot());
+    assert_eq!(up.dir().unwrap().entries(false, false)?[0].name(), "2022");
+    let notes = root_dir.get_path("2022/../2022/10/2B06C4C3.000")?;
+    assert_eq!(notes.load_bytes()?, b"play log");
+    let path = "2022/../2022/10/2B06C4C3.000";
+    assert_eq!(vff.borrow_mut().read_path(path)?, b"play log");
+    let raw = month.dir().unwrap().raw_entries(false)?;
+    assert!(raw[0].is_dot() && raw[1].is_dotdot());
+    assert!(!raw[2].is_dot() && !raw[2].is_dotdot());
+    Ok(())
+}
+
+impl ImageBuilder {
+    /// Build the VFAT long file name slots for `long_name`, in on-disk order
+    fn lfn_slots(long_name: &str, checksum: u8) -> Vec<[u8; 32]> {
+        let mut units: Vec<u16> = long_name.encode_utf16().collect();
+        if !units.len().is_multiple_of(13) {
+            units.push(0);
+        }
+        while !units.len().is_multiple_of(13) {
+            units.push(0xffff);
+        }
+        let parts: Vec<&[u16]> = units.chunks(13).collect();
+        let mut slots = Vec::new();
+        for (i, part) in parts.iter().enumerate().rev() {
+            let mut slot = [0u8; 32];
+            slot[0] = (i + 1) as u8 | if i == parts.len() - 1 { 0x40 } else { 0 };
+            slot[11] = 0xf;
+            slot[13] = checksum;
+            let offsets = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain((28..32).step_by(2));
+            for (unit, offset) in part.iter().zip(offsets) {
+                slot[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            slots.push(slot);
+        }
+        slots
+    }
+
+    fn checksum(entry: &[u8; 32]) -> u8 {
+        entry[0..11]
+            .iter()
+            .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+    }
+
+    /// Append a file preceded by VFAT long file name slots
+    fn add_lfn_file(
+        &mut self,
+        parent: Option<u16>,
+        long_name: &str,
+        short: (&str, &str),
+        contents: &[u8],
+    ) -> u16 {
+        let start = self.alloc(contents);
+        let entry = Self::raw_entry(short.0, short.1, 0x20, start, contents.len() as u32);
+        for slot in Self::lfn_slots(long_name, Self::checksum(&entry)) {
+            self.add_raw(parent, slot);
+        }
+        self.add_raw(parent, entry);
+        start
+    }
+}
+
+#[test]
+pub fn long_file_names() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_lfn_file(None, "cdb.config.bin", ("CDBCON~1", "BIN"), &[1, 2, 3]);
+    img.add_lfn_file(None, "short.txt", ("SHORT", "TXT"), b"abc");
+    // Slots whose checksum doesn't match the short entry are ignored
+    for slot in ImageBuilder::lfn_slots("mismatched name.log", 0) {
+        img.add_raw(None, slot);
+    }
+    img.add_file(None, "MISMAT~1", "LOG", b"x");
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let listing = root_dir.ls(false)?;
+    assert_eq!(
+        listing,
+        [
+            "/cdb.config.bin [0x0003]",
+            "/short.txt [0x0003]",
+            "/MISMAT~1.LOG [0x0001]"
+        ]
+    );
+    let by_long_name = root_dir.get_path("CDB.CONFIG.BIN")?;
+    assert_eq!(by_long_name.load_bytes()?, [1, 2, 3]);
+    assert_eq!(root_dir.get_path("CDBCON~1.BIN")?.load_bytes()?, [1, 2, 3]);
+    Ok(())
+}
+
+#[test]
+pub fn deleted_long_file_names() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    // A second cluster for the directory, so the deleted name straddles the boundary
+    let second = img.alloc(&[]);
+    img.set_fat(dir, second);
+    for i in 0..12 {
+        img.add_file(Some(dir), &format!("FILE{i}"), "TXT", b"");
+    }
+    let contents = b"recoverable";
+    let start = img.alloc(contents);
+    img.set_fat(start, 0);
+    let mut entry = ImageBuilder::raw_entry("PLAYLO~1", "TXT", 0x20, start, 11);
+    for mut slot in
+        ImageBuilder::lfn_slots("play log for recovery.txt", ImageBuilder::checksum(&entry))
+    {
+        slot[0] = 0xe5;
+        img.add_raw(Some(dir), slot);
+    }
+    entry[0] = 0xe5;
+    img.add_raw(Some(dir), entry);
+    // Live slots left over before a deleted name aren't mixed into it
+    img.add_raw(Some(dir), ImageBuilder::lfn_slots("stale", 0)[0]);
+    let mut entry = ImageBuilder::raw_entry("OTHER", "BIN", 0x20, 0, 0);
+    for mut slot in ImageBuilder::lfn_slots("other.bin", ImageBuilder::checksum(&entry)) {
+        slot[0] = 0xe5;
+        img.add_raw(Some(dir), slot);
+    }
+    entry[0] = 0xe5;
+    img.add_raw(Some(dir), entry);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let year = root_dir.get_path("2022")?;
+    let entries = year.dir().unwrap().entries(true, false)?;
+    let deleted: Vec<&str> = entries
+        .iter()
+        .filter(|e| e.is_deleted())
+        .map(|e| e.full_name())
+        .collect();
+    assert_eq!(deleted, ["play log for recovery.txt", "other.bin"]);
+    // Without show_deleted the slots are skipped along with the entry
+    assert_eq!(year.dir().unwrap().entries(false, false)?.len(), 12);
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-deleted-long-names");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    root_dir.recover_deleted(test_dir.clone())?;
+    let recovered = test_dir.join("2022/play log for recovery.txt");
+    assert_eq!(std::fs::read(recovered)?, contents);
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn verify_fats() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "A", "BIN", &[0xaa; 0x400]);
+    let (vff, _) = VFF::new(img.build())?;
+    assert!(vff.borrow().verify_fats()?.is_empty());
+
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "A", "BIN", &[0xaa; 0x400]);
+    // Corrupt the link from cluster 2 to 3 in the backup FAT only
+    img.data[IMG_FAT_OFFSET + IMG_FAT_SIZE + 4] = 0;
+    let (vff, root_dir) = VFF::new(img.build())?;
+    assert_eq!(vff.borrow().verify_fats()?, [2]);
+    // Reading still goes through the primary copy
+    assert_eq!(root_dir.get_path("A.BIN")?.load_bytes()?, [0xaa; 0x400]);
+    Ok(())
+}
+
+#[test]
+pub fn read_chain_with_fallback() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "A", "BIN", &[0xaa; 0x400]);
+    let (vff, _) = VFF::new(img.build())?;
+    let (data, copy) = vff.borrow_mut().read_chain_with_fallback(start.into())?;
+    assert_eq!(data, [0xaa; 0x400]);
+    assert_eq!(copy, FatCopy::Primary);
+
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "A", "BIN", &[0xaa; 0x400]);
+    // Break the chain in the primary FAT
+    img.data[IMG_FAT_OFFSET + 4] = 0;
+    let (vff, _) = VFF::new(img.build())?;
+    assert!(vff.borrow_mut().read_chain(start.into()).is_err());
+    let (data, copy) = vff.borrow_mut().read_chain_with_fallback(start.into())?;
+    assert_eq!(data, [0xaa; 0x400]);
+    assert_eq!(copy, FatCopy::Backup);
+    Ok(())
+}
+
+#[test]
+pub fn from_bytes() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let (vff, root_dir) = VFF::from_bytes(img.data)?;
+    assert_eq!(vff.borrow().header.cluster_size, IMG_CLUSTER_SIZE as u16);
+    assert_eq!(root_dir.ls(false)?, ["/CDB~1.CON [0x0004]"]);
+    assert!(VFF::from_bytes(vec![0; 0x10]).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn vff_options() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "CDB~1", "CON", &[9; IMG_CLUSTER_SIZE * 2]);
+    // Break the chain in the primary FAT only
+    img.data[IMG_FAT_OFFSET + 2 * start as usize] = 0;
+    img.data[IMG_FAT_OFFSET + 2 * start as usize + 1] = 0;
+    let mut nand = vec![0xff; 0x400];
+    nand.extend(img.build().into_inner());
+
+    let options = VffOptions::new().base_offset(0x400);
+    let (_, root_dir) = options.open(io::Cursor::new(nand.clone()))?;
+    let entry = root_dir.entries(false, false)?.remove(0);
+    assert!(entry.load_bytes().is_err());
+
+    let (vff, root_dir) = options
+        .clone()
+        .read_secondary_fat(true)
+        .open(io::Cursor::new(nand.clone()))?;
+    assert_eq!(
+        root_dir.get_path("CDB~1.CON")?.load_bytes()?,
+        [9; IMG_CLUSTER_SIZE * 2]
+    );
+    assert_eq!(vff.borrow().base_offset(), 0x400);
+
+    let (vff, root_dir) = options.lenient(true).open(io::Cursor::new(nand))?;
+    assert!(root_dir.get_path("CDB~1.CON")?.load_bytes().is_ok());
+    assert!(!vff.borrow().warnings().is_empty());
+    Ok(())
+}
+
+#[cfg(feature = "flate2")]
+#[test]
+pub fn from_gz() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "CDB~1", "CON", &[9; 4]);
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    gz.write_all(&img.build().into_inner())?;
+    let mut path = std::env::temp_dir();
+    path.push("WiiVFF-tests-cdb.vff.gz");
+    std::fs::write(&path, gz.finish()?)?;
+
+    let (_, root_dir) = VFF::from_gz(&path)?;
+    assert_eq!(root_dir.get_path("CDB~1.CON")?.load_bytes()?, [9; 4]);
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+pub fn new_at_offset() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "CDB~1", "CON", b"conf");
+    let mut nand = vec![0xffu8; 0x1234];
+    nand.extend(img.data);
+    let (vff, root_dir) = VFF::new_at_offset(std::io::Cursor::new(nand), 0x1234)?;
+    assert_eq!(vff.borrow().base_offset(), 0x1234);
+    assert_eq!(root_dir.get_path("CDB~1.CON")?.load_bytes()?, b"conf");
+    Ok(())
+}
+
+#[test]
+pub fn file_offset() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "A", "BIN", b"first");
+    let start = img.add_file(None, "B", "BIN", b"second");
+    let mut nand = vec![0xffu8; 0x1234];
+    nand.extend(img.data);
+    let (vff, _) = VFF::new_at_offset(std::io::Cursor::new(nand.clone()), 0x1234)?;
+
+    let offset = vff.borrow().file_offset(start.into())?;
+    let expected = 0x1234 + IMG_DATA_OFFSET + (start as usize - 2) * IMG_CLUSTER_SIZE;
+    assert_eq!(offset, expected as u64);
+    assert_eq!(&nand[expected..expected + 6], b"second");
+    for start in [0, 1, IMG_CLUSTER_COUNT as u32] {
+        assert!(vff.borrow().file_offset(start).is_err());
+    }
+    Ok(())
+}
+
+#[test]
+pub fn cluster_counts() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "LOGS");
+    img.add_file(Some(dir), "A", "BIN", &[1; 0x300]);
+    img.add_file(None, "EMPTY", "BIN", &[]);
+    img.set_fat(0x100, 0xfff7);
+    let (vff, _) = VFF::new(img.build())?;
+    let vff = vff.borrow();
+    let fat = vff.fat()?;
+    assert_eq!(fat.fattype(), &SupportedFAT::FAT16);
+    assert_eq!(fat.used_cluster_count(), 3);
+    assert_eq!(fat.bad_cluster_count(), 1);
+    assert_eq!(fat.bad_clusters(), [0x100]);
+    assert_eq!(vff.bad_clusters()?, [0x100]);
+    let free = IMG_CLUSTER_COUNT as u32 - 2 - 3 - 1;
+    assert_eq!(fat.free_cluster_count(), free);
+    assert_eq!(
+        vff.free_space_bytes()?,
+        free as u64 * IMG_CLUSTER_SIZE as u64
+    );
+    Ok(())
+}
+
+#[test]
+pub fn listing_tree() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let year = img.add_dir(None, "2022");
+    img.add_file(Some(year), "2B06C4C3", "000", &[0; 0xca0]);
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let mut deleted = ImageBuilder::raw_entry("OLD", "LOG", 0x20, 0, 0);
+    deleted[0] = 0xe5;
+    img.add_raw(None, deleted);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let listing = root_dir.listing(false)?;
+    assert_eq!(listing.len(), 2);
+    assert_eq!(listing[0].path, "/2022");
+    assert_eq!(listing[0].children[0].path, "/2022/2B06C4C3.000");
+    assert_eq!(listing[0].children[0].size, 0xca0);
+    assert_eq!(listing[1].name, "CDB~1.CON");
+
+    let with_deleted = root_dir.listing(true)?;
+    assert_eq!(with_deleted.len(), 3);
+    assert!(with_deleted[2].deleted);
+    Ok(())
+}
+
+#[test]
+pub fn visit_and_dump() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let year = img.add_dir(None, "2022");
+    let month = img.add_dir(Some(year), "10");
+    img.add_file(Some(month), "2B06C4C3", "000", &[7; 0x500]);
+    img.add_dir(Some(year), "11");
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let mut visited = Vec::new();
+    root_dir.visit(false, &mut |entry| {
+        visited.push(entry.path().to_owned() + "/" + entry.full_name());
+        Ok(())
+    })?;
+    assert_eq!(
+        visited,
+        [
+            "/2022",
+            "/2022/10",
+            "/2022/10/2B06C4C3.000",
+            "/2022/11",
+            "/CDB~1.CON"
+        ]
+    );
+    assert_eq!(
+        root_dir.ls(false)?,
+        [
+            "/2022/10/2B06C4C3.000 [0x0500]",
+            "/2022/11",
+            "/CDB~1.CON [0x0004]"
+        ]
+    );
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-visit");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    root_dir
+        .get_path("2022")?
+        .dir()
+        .unwrap()
+        .dump(test_dir.clone(), false)?;
+    assert_eq!(std::fs::read(test_dir.join("10/2B06C4C3.000"))?, [7; 0x500]);
+    assert!(test_dir.join("11").is_dir());
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn directory_cycles() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "LOOP");
+    img.add_file(Some(dir), "A", "BIN", b"inside");
+    img.add_raw(Some(dir), ImageBuilder::raw_entry("SELF", "", 0x10, dir, 0));
+    img.add_raw(None, ImageBuilder::raw_entry("ROOT", "", 0x10, 0, 0));
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    assert_eq!(
+        root_dir.ls(false)?,
+        ["/LOOP/A.BIN [0x0006]", "/LOOP/SELF", "/ROOT"]
+    );
+    assert_eq!(
+        vff.borrow().warnings()[..2],
+        [
+            Warning::DirectoryCycle {
+                path: "/ROOT".to_owned(),
+                start: 0,
+            },
+            Warning::DirectoryCycle {
+                path: "/LOOP/SELF".to_owned(),
+                start: dir.into(),
+            },
+        ]
+    );
+    // Going back up through `..` isn't a cycle
+    let path = "LOOP/../LOOP/A.BIN";
+    assert_eq!(root_dir.get_path(path)?.load_bytes()?, b"inside");
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-directory-cycles");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    root_dir.dump(test_dir.clone(), false)?;
+    assert_eq!(std::fs::read(test_dir.join("LOOP/A.BIN"))?, b"inside");
+    assert!(test_dir.join("LOOP/SELF").is_dir());
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn dump_name_styles() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_lfn_file(Some(dir), "cdb.config.bin", ("CDBCON~1", "BIN"), &[1, 2, 3]);
+    img.add_file(Some(dir), "PLAY", "LOG", b"hello");
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-name-styles");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    let short = root_dir.dump_named(test_dir.join("short"), false, &|_| true, NameStyle::Short)?;
+    assert!(short.is_empty());
+    assert_eq!(
+        std::fs::read(test_dir.join("short/2022/CDBCON~1.BIN"))?,
+        [1, 2, 3]
+    );
+    assert!(test_dir.join("short/2022/PLAY.LOG").exists());
+    assert!(!test_dir.join("short/2022/cdb.config.bin").exists());
+
+    let both = root_dir.dump_named(test_dir.join("both"), false, &|_| true, NameStyle::Both)?;
+    assert_eq!(
+        both,
+        [("2022/cdb.config.bin".to_owned(), "CDBCON~1.BIN".to_owned())]
+    );
+    assert_eq!(
+        std::fs::read(test_dir.join("both/2022/cdb.config.bin"))?,
+        [1, 2, 3]
+    );
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn dump_name_collisions() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "SAVE", "TXT", b"short");
+    img.add_lfn_file(None, "Save.txt", ("SAVE~1", "TXT"), b"long");
+    let dir = img.add_dir(None, "DATA");
+    img.add_file(Some(dir), "A", "BIN", b"a");
+    img.add_lfn_file(None, "data", ("DATA~1", ""), b"file");
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-name-collisions");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    root_dir.dump(test_dir.clone(), false)?;
+    assert_eq!(std::fs::read(test_dir.join("SAVE.TXT"))?, b"short");
+    assert_eq!(std::fs::read(test_dir.join("Save (2).txt"))?, b"long");
+    assert_eq!(std::fs::read(test_dir.join("DATA/A.BIN"))?, b"a");
+    assert_eq!(std::fs::read(test_dir.join("data (2)"))?, b"file");
+    assert_eq!(
+        vff.borrow().warnings(),
+        [
+            Warning::NameCollision {
+                path: "Save.txt".to_owned(),
+                name: "Save (2).txt".to_owned()
+            },
+            Warning::NameCollision {
+                path: "data".to_owned(),
+                name: "data (2)".to_owned()
+            }
+        ]
+    );
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn dump_unsafe_names() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    // A directory whose long name is `..`, which mustn't take its contents up a level
+    let dir = img.alloc(&[]);
+    let entry = ImageBuilder::raw_entry("DOTDOT~1", "", 0x10, dir, 0);
+    for slot in ImageBuilder::lfn_slots("..", ImageBuilder::checksum(&entry)) {
+        img.add_raw(None, slot);
+    }
+    img.add_raw(None, entry);
+    img.add_raw(Some(dir), ImageBuilder::raw_entry(".", "", 0x10, dir, 0));
+    img.add_raw(Some(dir), ImageBuilder::raw_entry("..", "", 0x10, 0, 0));
+    img.add_file(Some(dir), "PWNED", "TXT", b"escaped");
+    img.add_lfn_file(None, "a/b\\c", ("ABC~1", ""), b"slashes");
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-unsafe-names");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    let out = test_dir.join("out");
+    root_dir.dump(out.clone(), false)?;
+    assert!(!test_dir.join("PWNED.TXT").exists());
+    assert_eq!(std::fs::read(out.join("__/PWNED.TXT"))?, b"escaped");
+    assert_eq!(std::fs::read(out.join("a_b_c"))?, b"slashes");
+    assert_eq!(
+        vff.borrow().warnings(),
+        [
+            Warning::UnsafeName {
+                path: "..".to_owned(),
+                name: "__".to_owned()
+            },
+            Warning::UnsafeName {
+                path: "a/b\\c".to_owned(),
+                name: "a_b_c".to_owned()
+            }
+        ]
+    );
+
+    let flat = test_dir.join("flat");
+    root_dir.dump_flat(flat.clone(), false, &|_| true, NameStyle::Long, false)?;
+    assert_eq!(std::fs::read(flat.join("___PWNED.TXT"))?, b"escaped");
+    assert_eq!(std::fs::read(flat.join("a_b_c"))?, b"slashes");
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn dump_flat() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    let sub = img.add_dir(Some(dir), "10");
+    img.add_lfn_file(Some(sub), "play.log", ("PLAY", "LOG"), b"nested");
+    img.add_lfn_file(None, "2022_10_play.log", ("2022_1~1", "LOG"), b"top");
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-dump-flat");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    let report = root_dir.dump_flat(
+        test_dir.join("long"),
+        false,
+        &|_| true,
+        NameStyle::Long,
+        false,
+    )?;
+    assert!(report.aliases.is_empty() && report.failed.is_empty());
+    let mut names: Vec<_> = std::fs::read_dir(test_dir.join("long"))?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    names.sort();
+    assert_eq!(names, ["2022_10_play (2).log", "2022_10_play.log"]);
+    assert_eq!(
+        std::fs::read(test_dir.join("long/2022_10_play.log"))?,
+        b"nested"
+    );
+    assert_eq!(
+        std::fs::read(test_dir.join("long/2022_10_play (2).log"))?,
+        b"top"
+    );
+    assert_eq!(vff.borrow().warnings().len(), 1);
+
+    root_dir.dump_flat(
+        test_dir.join("short"),
+        false,
+        &|_| true,
+        NameStyle::Short,
+        true,
+    )?;
+    assert_eq!(
+        std::fs::read(test_dir.join("short/2022_10_PLAY.LOG"))?,
+        b"nested"
+    );
+    assert_eq!(std::fs::read(test_dir.join("short/2022_1~1.LOG"))?, b"top");
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn dump_skipping_errors() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "GOOD", "BIN", &[1; 4]);
+    let broken = img.add_file(None, "BROKEN", "BIN", &[2; IMG_CLUSTER_SIZE * 2]);
+    img.set_fat(broken, 0);
+    img.add_file(None, "AFTER", "BIN", &[3; 4]);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-skip-errors");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    assert!(root_dir.dump(test_dir.clone(), false).is_err());
+    assert!(!test_dir.join("AFTER.BIN").exists());
+
+    let failed = root_dir
+        .dump_named_skipping_errors(test_dir.clone(), false, &|_| true, NameStyle::Long)?
+        .failed;
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].0, "BROKEN.BIN");
+    assert_eq!(std::fs::read(test_dir.join("GOOD.BIN"))?, [1; 4]);
+    assert_eq!(std::fs::read(test_dir.join("AFTER.BIN"))?, [3; 4]);
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn dump_with_progress() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_file(Some(dir), "PLAY", "LOG", b"hello");
+    img.add_file(None, "BIG", "BIN", &[1; IMG_CLUSTER_SIZE * 2]);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-progress");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    let mut events = Vec::new();
+    root_dir.dump_with_progress(test_dir.clone(), false, &mut |e| events.push(e))?;
+    let discovered = |path: &str, size| ProgressEvent::Discovered {
+        path: path.to_owned(),
+        size,
+    };
+    let written = |path: &str, bytes| ProgressEvent::Written {
+        path: path.to_owned(),
+        bytes,
+    };
+    assert_eq!(
+        events,
+        [
+            discovered("2022/PLAY.LOG", 5),
+            discovered("BIG.BIN", IMG_CLUSTER_SIZE as u32 * 2),
+            written("2022/PLAY.LOG", 5),
+            written("BIG.BIN", IMG_CLUSTER_SIZE as u64 * 2),
+        ]
+    );
+    assert_eq!(std::fs::read(test_dir.join("2022/PLAY.LOG"))?, b"hello");
+    assert_eq!(root_dir.total_size(false)?, 5 + IMG_CLUSTER_SIZE as u64 * 2);
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn reentrancy() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_file(Some(dir), "PLAY", "LOG", b"hello");
+    img.add_file(None, "BIG", "BIN", &[1; IMG_CLUSTER_SIZE * 2]);
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    // The visitor can read files and borrow the VFF while the walk is going
+    let mut seen = Vec::new();
+    root_dir.visit(false, &mut |entry| {
+        if !entry.is_directory() {
+            seen.push(entry.load_bytes()?.len());
+            root_dir.get_path("2022/PLAY.LOG")?.load_bytes()?;
+            vff.borrow_mut().take_warnings();
+        }
+        Ok(())
+    })?;
+    assert_eq!(seen, [5, IMG_CLUSTER_SIZE * 2]);
+
+    // Readers on different files can be interleaved
+    let big = root_dir.get_path("BIG.BIN")?;
+    let play = root_dir.get_path("2022/PLAY.LOG")?;
+    let (mut big, mut play) = (big.reader()?, play.reader()?);
+    let mut buf = [0; 3];
+    big.read_exact(&mut buf)?;
+    play.read_exact(&mut buf)?;
+    assert_eq!(&buf, b"hel");
+    big.read_exact(&mut buf)?;
+    assert_eq!(buf, [1; 3]);
+    Ok(())
+}
+
+#[test]
+pub fn non_send_input() -> Result<()> {
+    // Only `open_owned` needs the input to be `Send`
+    #[derive(Debug)]
+    struct Shared(std::rc::Rc<RefCell<std::io::Cursor<Vec<u8>>>>);
+    impl Read for Shared {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().read(buf)
+        }
+    }
+    impl Seek for Shared {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.0.borrow_mut().seek(pos)
+        }
+    }
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "PLAY", "LOG", b"hello");
+    let input = std::rc::Rc::new(RefCell::new(img.build()));
+    let (_, root_dir) = VFF::new(Shared(input.clone()))?;
+    assert_eq!(root_dir.read_file("PLAY.LOG")?, b"hello");
+    let (_, root_dir) = VffOptions::new().open(Shared(input))?;
+    assert_eq!(root_dir.ls(false)?, ["/PLAY.LOG [0x0005]"]);
+    Ok(())
+}
+
+#[test]
+pub fn shared_across_threads() -> Result<()> {
+    fn assert_send<T: Send>() {}
+    assert_send::<VFF<Sendable>>();
+
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_file(Some(dir), "PLAY", "LOG", b"hello");
+    img.add_file(None, "BIG", "BIN", &[1; IMG_CLUSTER_SIZE * 2]);
+    let vff = VffOptions::new().open_owned(img.build())?;
+    let vff = std::sync::Arc::new(std::sync::Mutex::new(vff));
+
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let vff = vff.clone();
+            std::thread::spawn(move || -> Result<()> {
+                let mut vff = vff.lock().unwrap();
+                assert_eq!(vff.read_path("/2022/play.log")?, b"hello");
+                assert_eq!(vff.read_path("BIG.BIN")?, [1; IMG_CLUSTER_SIZE * 2]);
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+
+    let mut vff = vff.lock().unwrap();
+    let names: Vec<_> = vff
+        .list_path("/", false)?
+        .iter()
+        .map(|e| e.nice_full_name())
+        .collect();
+    assert_eq!(names, ["2022", "BIG.BIN"]);
+    let names: Vec<_> = vff
+        .list_path("2022", false)?
+        .iter()
+        .map(|e| e.nice_full_name())
+        .collect();
+    assert_eq!(names, ["PLAY.LOG"]);
+    assert!(vff.list_path("BIG.BIN", false).is_err());
+    assert!(vff.read_path("2022").is_err());
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "already")]
+pub fn reading_while_borrowed() {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "PLAY", "LOG", b"hello");
+    let (vff, root_dir) = VFF::new(img.build()).unwrap();
+    let entries = root_dir.entries(false, false).unwrap();
+    let _header = vff.borrow();
+    let _ = entries[0].load_bytes();
+}
+
+#[test]
+pub fn cluster_number_checks() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    // A chain that starts on a reserved cluster, whose entry reads as end of chain
+    img.add_raw(None, ImageBuilder::raw_entry("ONE", "BIN", 0x20, 1, 4));
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    for cluster in [0, 1, IMG_CLUSTER_COUNT as u32, u32::MAX] {
+        let Err(VFFError::InvalidData { context, .. }) = vff.borrow().read_cluster(cluster) else {
+            panic!("reading cluster {cluster:#x} should fail cleanly");
+        };
+        assert_eq!(context, "Locating a data cluster");
+    }
+    assert!(vff.borrow().read_cluster(2).is_ok());
+    assert!(matches!(
+        root_dir.get_path("ONE.BIN"),
+        Err(VFFError::InvalidData { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+pub fn chain_into_fat_padding() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    // Two clusters fewer, which leaves the FAT the same size with two padding entries at the end
+    let cluster_count = IMG_CLUSTER_COUNT as u16 - 2;
+    let volume_size = IMG_CLUSTER_SIZE as u32 * cluster_count as u32;
+    img.data[8..12].copy_from_slice(&volume_size.to_be_bytes());
+    let last = cluster_count - 1;
+    img.set_fat(last, last + 1);
+    img.set_fat(last + 1, 0xffff);
+    img.add_raw(
+        None,
+        ImageBuilder::raw_entry("PAD", "BIN", 0x20, last, IMG_CLUSTER_SIZE as u32 * 2),
+    );
+    let (vff, root_dir) = VFF::new(img.build())?;
+    assert_eq!(vff.borrow().header().cluster_count, cluster_count.into());
+
+    // The chain itself is fine as far as the FAT goes, but its second cluster has no data
+    assert_eq!(vff.borrow_mut().get_chain(last.into())?.len(), 2);
+    let Err(VFFError::InvalidData { context, .. }) = root_dir.read_file("PAD.BIN") else {
+        panic!("reading past the data area should fail");
+    };
+    assert_eq!(context, "Locating a data cluster");
+    Ok(())
+}
+
+#[test]
+pub fn read_file_range() -> Result<()> {
+    let contents: Vec<u8> = (0..IMG_CLUSTER_SIZE * 3 + 0x20)
+        .map(|x| (x / 3) as u8)
+        .collect();
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "BIG", "BIN", &contents);
+    let (vff, _) = VFF::new(img.build())?;
+    let vff = vff.borrow();
+    let (start, size) = (start as u32, contents.len() as u32);
+
+    let ranges = [
+        (0, 0x10),
+        (0x1f0, 0x20),
+        (0x100, IMG_CLUSTER_SIZE * 2 + 0x10),
+        (size as u64 - 5, 5),
+        (0x40, 0),
+    ];
+    for (offset, len) in ranges {
+        let expected = &contents[offset as usize..offset as usize + len];
+        assert_eq!(vff.read_file_range(start, size, offset, len)?, expected);
+    }
+    assert!(vff
+        .read_file_range(start, size, size as u64 - 5, 6)
+        .is_err());
+    assert!(vff.read_file_range(start, size, u64::MAX, 1).is_err());
+    // A size that claims more clusters than the chain has
+    assert!(vff
+        .read_file_range(start, size * 2, size as u64 * 2 - 1, 1)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+pub fn diff() -> Result<()> {
+    let mut old = ImageBuilder::new();
+    let dir = old.add_dir(None, "2022");
+    old.add_file(Some(dir), "SAME", "BIN", &[1; IMG_CLUSTER_SIZE * 2]);
+    old.add_file(Some(dir), "EDITED", "BIN", &[2; IMG_CLUSTER_SIZE * 2]);
+    old.add_file(None, "RESIZED", "BIN", &[3; 4]);
+    old.add_file(None, "GONE", "BIN", &[4; 4]);
+    let (_, old_root) = VFF::new(old.build())?;
+
+    let mut new = ImageBuilder::new();
+    // Different clusters than in the old image shouldn't matter
+    new.add_file(None, "RESIZED", "BIN", &[3; 5]);
+    let dir = new.add_dir(None, "2022");
+    let mut edited = vec![2; IMG_CLUSTER_SIZE * 2];
+    edited[IMG_CLUSTER_SIZE + 1] = 0;
+    new.add_file(Some(dir), "EDITED", "BIN", &edited);
+    new.add_file(Some(dir), "SAME", "BIN", &[1; IMG_CLUSTER_SIZE * 2]);
+    new.add_file(Some(dir), "NEW", "BIN", &[5; 4]);
+    let (_, new_root) = VFF::new(new.build())?;
+
+    assert_eq!(
+        old_root.diff(&new_root)?,
+        [
+            DiffEntry::Modified("2022/EDITED.BIN".to_owned()),
+            DiffEntry::Added("2022/NEW.BIN".to_owned()),
+            DiffEntry::Removed("GONE.BIN".to_owned()),
+            DiffEntry::Modified("RESIZED.BIN".to_owned()),
+        ]
+    );
+    assert!(old_root.diff(&old_root)?.is_empty());
+    Ok(())
+}
+
+#[test]
+pub fn read_directory() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_file(Some(dir), "PLAY", "LOG", b"hello");
+    // Drop the entry from the root so the directory is only reachable by its cluster
+    img.data[IMG_ROOT_OFFSET] = 0xe5;
+    let (vff, root_dir) = VFF::new(img.build())?;
+    assert!(root_dir.entries(false, false)?.is_empty());
+
+    let found = VFF::read_directory(&vff, dir.into(), "/FOUND".to_owned())?;
+    assert_eq!(found.ls(false)?, ["/FOUND/PLAY.LOG [0x0005]"]);
+    assert_eq!(found.get_path("PLAY.LOG")?.load_bytes()?, b"hello");
+    Ok(())
+}
+
+#[test]
+pub fn unaligned_directory() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_file(Some(dir), "PLAY", "LOG", b"hello");
+    let (vff, _) = VFF::new(img.build())?;
+    let mut data = vff.borrow_mut().read_chain(dir.into())?;
+    // Cut partway into the entry after PLAY.LOG
+    data.truncate(32 * 3 + 7);
+
+    assert!(Directory::new(vff.clone(), data.clone(), "/2022".to_owned()).is_err());
+    vff.borrow_mut().set_lenient(true);
+    let recovered = Directory::new(vff.clone(), data, "/2022".to_owned())?;
+    assert_eq!(recovered.ls(false)?, ["/2022/PLAY.LOG [0x0005]"]);
+    assert_eq!(
+        vff.borrow_mut().take_warnings(),
+        [Warning::UnalignedDirectory {
+            path: "/2022".to_owned(),
+            len: 32 * 3 + 7
+        }]
+    );
+    Ok(())
+}
+
+#[test]
+pub fn list_dir() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let year = img.add_dir(None, "2022");
+    let month = img.add_dir(Some(year), "10");
+    img.add_file(Some(month), "PLAY", "LOG", b"hello");
+    img.add_file(Some(year), "NOTES", "TXT", b"notes");
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let (vff, _) = VFF::new(img.build())?;
+
+    let names = |path| -> Result<Vec<String>> {
+        Ok(VFF::list_dir(&vff, path, false)?
+            .iter()
+            .map(|e| e.full_name().to_owned())
+            .collect())
+    };
+    assert_eq!(names("/2022")?, ["10", "NOTES.TXT"]);
+    assert_eq!(names("2022/10")?, ["PLAY.LOG"]);
+    assert_eq!(names("/")?, ["2022", "CDB~1.CON"]);
+    assert!(VFF::list_dir(&vff, "/2022/NOTES.TXT", false).is_err());
+    assert!(VFF::list_dir(&vff, "/2023", false).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn write_file() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    let start = img.add_file(Some(dir), "CONFIG", "BIN", &[1; IMG_CLUSTER_SIZE * 3]);
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let image = img.build();
+
+    let (vff, _) = VFF::new(image.clone())?;
+    assert!(vff
+        .borrow_mut()
+        .write_file("2022/CONFIG.BIN", b"x")
+        .is_err());
+
+    let (vff, _) = VffOptions::new().open_writable(image)?;
+    let patched: Vec<u8> = (0..IMG_CLUSTER_SIZE + 0x10).map(|x| x as u8).collect();
+    vff.borrow_mut().write_file("/2022/config.bin", &patched)?;
+    let entries = VFF::list_dir(&vff, "2022", false)?;
+    assert_eq!(entries[0].size(), patched.len() as u32);
+    assert_eq!(entries[0].load_bytes()?, patched);
+    // The third cluster isn't needed anymore
+    let start = start as u32;
+    assert_eq!(vff.borrow().fat()?.get_cluster(start + 1)?, 0xffff);
+    assert_eq!(vff.borrow().backup_fat()?.get_cluster(start + 2)?, 0);
+    assert!(vff.borrow().verify_fats()?.is_empty());
+
+    let too_big = vec![0; IMG_CLUSTER_SIZE * IMG_CLUSTER_COUNT];
+    assert!(vff
+        .borrow_mut()
+        .write_file("2022/CONFIG.BIN", &too_big)
+        .is_err());
+    assert!(vff.borrow_mut().write_file("2022", b"").is_err());
+
+    vff.borrow_mut().write_file("CDB~1.CON", b"")?;
+    let root = VFF::list_dir(&vff, "/", false)?;
+    assert_eq!(root[1].size(), 0);
+    assert_eq!(root[1].start_cluster(), Some(0));
+    Ok(())
+}
+
+#[test]
+pub fn archive_attribute() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let archived = img.alloc(b"saved");
+    let hidden = img.alloc(b"secret");
+    img.add_raw(
+        None,
+        ImageBuilder::raw_entry("SAVED", "BIN", 0x21, archived, 5),
+    );
+    img.add_raw(
+        None,
+        ImageBuilder::raw_entry("HIDDEN", "BIN", 0x02, hidden, 6),
+    );
+    let (vff, root_dir) = VffOptions::new().open_writable(img.build())?;
+
+    let raw = root_dir.read(false)?;
+    assert_eq!((raw[0].attr, raw[1].attr), (0x21, 0x02));
+    let entries = root_dir.entries(false, false)?;
+    assert!(entries[0].is_archive() && entries[0].is_readonly());
+    assert!(!entries[1].is_archive());
+    let info = root_dir.list_detailed(false)?;
+    assert!(info[0].is_archive() && !info[1].is_archive());
+
+    // Writing a file marks it for the next backup and keeps its other attributes
+    vff.borrow_mut().write_file("HIDDEN.BIN", b"changed")?;
+    let entries = VFF::list_dir(&vff, "/", false)?;
+    assert_eq!(entries[1].attributes(), 0x22);
+    assert_eq!(entries[0].attributes(), 0x21);
+    Ok(())
+}
+
+#[test]
+pub fn grow_file() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "SMALL", "BIN", &[1; 4]);
+    img.add_file(None, "OTHER", "BIN", &[2; IMG_CLUSTER_SIZE]);
+    let empty = img.add_file(None, "EMPTY", "BIN", &[]);
+    assert_eq!(empty, 0);
+    let (vff, _) = VffOptions::new().open_writable(img.build())?;
+
+    let grown: Vec<u8> = (0..IMG_CLUSTER_SIZE * 3).map(|x| (x / 7) as u8).collect();
+    vff.borrow_mut().write_file("SMALL.BIN", &grown)?;
+    vff.borrow_mut().write_file("EMPTY.BIN", b"new")?;
+    let root = VFF::list_dir(&vff, "/", false)?;
+    assert_eq!(root[0].load_bytes()?, grown);
+    assert_eq!(root[1].load_bytes()?, vec![2; IMG_CLUSTER_SIZE]);
+    assert_eq!(root[2].load_bytes()?, b"new");
+    // The file keeps its first cluster and continues past the one after it
+    let chain = vff.borrow_mut().get_chain(start as u32)?;
+    assert_eq!(chain.len(), 3);
+    assert_eq!(chain[0], start as u32);
+    assert!(vff.borrow().verify_fats()?.is_empty());
+
+    let free = vff.borrow().fat()?.free_cluster_count();
+    assert!(vff.borrow_mut().allocate_chain(free as usize + 1).is_err());
+    assert_eq!(vff.borrow().fat()?.free_cluster_count(), free);
+    let chain = vff.borrow_mut().allocate_chain(2)?;
+    assert_eq!(vff.borrow().fat()?.get_cluster(chain[0])?, chain[1]);
+    let backup_last = vff.borrow().backup_fat()?.get_cluster(chain[1])?;
+    assert!(vff.borrow().fat()?.is_last(backup_last));
+    assert!(vff
+        .borrow()
+        .write_cluster(chain[0], &[0; IMG_CLUSTER_SIZE + 1])
+        .is_err());
+    assert!(vff.borrow().write_cluster(1, b"x").is_err());
+    Ok(())
+}
+
+#[test]
+pub fn fat16_predicates() -> Result<()> {
+    let (vff, _) = VFF::new(ImageBuilder::new().build())?;
+    let vff = vff.borrow();
+    let fat = vff.fat()?;
+    let classify = |x: u32| {
+        [
+            fat.is_available(x),
+            fat.is_used(x),
+            fat.is_bad(x),
+            fat.is_last(x),
+        ]
+    };
+    let boundaries = [
+        (0x0000, [true, false, false, false]),
+        (0x0001, [false, true, false, false]),
+        (0x0002, [false, true, false, false]),
+        (0xffef, [false, true, false, false]),
+        (0xfff0, [false, false, false, true]),
+        (0xfff6, [false, false, false, true]),
+        (0xfff7, [false, false, true, false]),
+        (0xfff8, [false, false, false, true]),
+        (0xffff, [false, false, false, true]),
+    ];
+    for (value, expected) in boundaries {
+        assert_eq!(classify(value), expected, "{value:#06x}");
+    }
+    // Exactly one predicate holds for every FAT16 value
+    for value in 0..=0xffff {
+        assert_eq!(classify(value).iter().filter(|x| **x).count(), 1);
+    }
+    Ok(())
+}
+
+#[test]
+pub fn fat_map() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "TWO", "BIN", &[1; IMG_CLUSTER_SIZE * 2]);
+    let bad = img.add_file(None, "BAD", "BIN", &[2; 4]);
+    img.set_fat(bad, 0xfff7);
+    let (vff, _) = VFF::new(img.build())?;
+    let vff = vff.borrow();
+
+    let raw = vff.fat()?.as_slice();
+    assert_eq!(raw.len(), IMG_FAT_SIZE / 2);
+    assert_eq!(raw[..6], [0xfff8, 0xffff, 3, 0xffff, 0xfff7, 0]);
+
+    let map = vff.fat_map()?;
+    assert_eq!(map.len(), IMG_CLUSTER_COUNT);
+    use ClusterState::*;
+    assert_eq!(map[..6], [Reserved, Reserved, Used, Eoc, Bad, Free]);
+    assert!(map[5..].iter().all(|state| *state == Free));
+    Ok(())
+}
+
+#[test]
+pub fn chain_terminators() -> Result<()> {
+    let terminators = [0xffff, 0xfff8, 0xfffb, 0xfff0, 0xfff6];
+    let mut img = ImageBuilder::new();
+    for (i, &terminator) in terminators.iter().enumerate() {
+        let start = img.add_file(
+            None,
+            &format!("FILE{i}"),
+            "BIN",
+            &[i as u8; IMG_CLUSTER_SIZE * 2],
+        );
+        img.set_fat(start + 1, terminator);
+    }
+    let bad = img.add_file(None, "BAD", "BIN", &[9; IMG_CLUSTER_SIZE * 2]);
+    img.set_fat(bad + 1, 0xfff7);
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    for (i, &terminator) in terminators.iter().enumerate() {
+        assert!(vff.borrow().fat()?.is_last(terminator.into()));
+        let entry = root_dir.get_path(&format!("FILE{i}.BIN"))?;
+        assert_eq!(entry.load_bytes()?, vec![i as u8; IMG_CLUSTER_SIZE * 2]);
+    }
+    let vff = vff.borrow();
+    let fat = vff.fat()?;
+    assert!(!fat.is_last(0xfff7));
+    assert!(!fat.is_last(0xffef));
+    assert!(fat.get_chain(bad as u32).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn delete() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    let start = img.add_file(Some(dir), "CONFIG", "BIN", &[1; IMG_CLUSTER_SIZE * 2]);
+    img.add_file(None, "CDB~1", "CON", &[2; 4]);
+    let broken = img.add_file(None, "BROKEN", "BIN", &[3; IMG_CLUSTER_SIZE * 2]);
+    img.set_fat(broken, 0xfff7);
+    let mut path = std::env::temp_dir();
+    path.push("WiiVFF-tests-delete.vff");
+    std::fs::write(&path, img.build().into_inner())?;
+
+    let (vff, _) = VFF::from_path(&path)?;
+    assert!(vff.borrow_mut().delete("CDB~1.CON").is_err());
+
+    let (vff, _) = VFF::from_path_writable(&path)?;
+    assert!(vff.borrow_mut().delete("2022").is_err());
+    assert!(vff.borrow_mut().delete("2022/MISSING.BIN").is_err());
+    // A chain that can't be followed leaves the file alone
+    assert!(vff.borrow_mut().delete("BROKEN.BIN").is_err());
+    assert!(VFF::list_dir(&vff, "", false)?
+        .iter()
+        .any(|e| e.full_name() == "BROKEN.BIN"));
+    assert_eq!(vff.borrow().fat()?.get_cluster(broken.into())?, 0xfff7);
+    assert_eq!(vff.borrow().fat()?.get_cluster(broken as u32 + 1)?, 0xffff);
+    vff.borrow_mut().delete("2022/CONFIG.BIN")?;
+    let start = start as u32;
+    assert_eq!(vff.borrow().fat()?.get_cluster(start)?, 0);
+    assert_eq!(vff.borrow().backup_fat()?.get_cluster(start + 1)?, 0);
+    assert!(vff.borrow().verify_fats()?.is_empty());
+    drop(vff);
+
+    let (vff, root_dir) = VFF::from_path(&path)?;
+    let live: Vec<_> = root_dir
+        .ls_entries(false)?
+        .into_iter()
+        .map(|e| e.path)
+        .collect();
+    assert_eq!(live, ["/2022", "/CDB~1.CON", "/BROKEN.BIN"]);
+    let deleted: Vec<_> = root_dir
+        .ls_entries(true)?
+        .into_iter()
+        .filter(|e| e.deleted)
+        .map(|e| e.path)
+        .collect();
+    assert_eq!(deleted, ["/2022/\u{fffd}ONFIG.BIN"]);
+    assert!(matches!(
+        root_dir.get_path("2022/CONFIG.BIN")?.content,
+        DirectoryContent::NoContent
+    ));
+    assert_eq!(vff.borrow().fat()?.used_cluster_count(), 3);
+    drop((vff, root_dir));
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+pub fn create() -> Result<()> {
+    let mut path = std::env::temp_dir();
+    path.push("WiiVFF-tests-create.vff");
+    let _ = std::fs::remove_file(&path);
+    assert!(VFF::create(&path, 0x1400000, 0x208).is_err());
+    assert!(VFF::create(&path, 0x1400000, 0).is_err());
+    assert!(VFF::create(&path, 0x1400000, 0x300).is_err());
+    // Too few clusters for FAT16, then too many
+    assert!(VFF::create(&path, 0x800 * 0x10, 0x10).is_err());
+    assert!(VFF::create(&path, 0x10000 * 0x10, 0x10).is_err());
+    assert!(!path.exists());
+
+    let (vff, root_dir) = VFF::create(&path, 0x1400000, 0x200)?;
+    assert!(root_dir.entries(true, false)?.is_empty());
+    assert!(VFF::create(&path, 0x1400000, 0x200).is_err());
+    drop((vff, root_dir));
+
+    let (vff, root_dir) = VFF::from_path(&path)?;
+    assert!(root_dir.entries(true, false)?.is_empty());
+    let vff = vff.borrow();
+    assert_eq!(vff.header().volume_size, 0x1400000);
+    assert_eq!(vff.header().cluster_size, 0x200);
+    assert_eq!(vff.header().cluster_count, 0xa000);
+    assert_eq!(vff.fat()?.used_cluster_count(), 0);
+    assert_eq!(vff.fat()?.free_cluster_count(), 0xa000 - 2);
+    assert!(vff.verify_fats()?.is_empty());
+    assert_eq!(std::fs::metadata(&path)?.len(), 0x1400000);
+    drop(vff);
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+pub fn file_reader() -> Result<()> {
+    let contents: Vec<u8> = (0..0x6a1).map(|x| x as u8).collect();
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "BIG", "BIN", &contents);
+    img.add_file(None, "EMPTY", "BIN", &[]);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let entry = root_dir.get_path("BIG.BIN")?;
+    let mut streamed = Vec::new();
+    entry.reader()?.read_to_end(&mut streamed)?;
+    assert_eq!(streamed, contents);
+
+    // Small reads that straddle cluster boundaries
+    let mut reader = entry.reader()?;
+    let mut chunk = [0u8; 0x123];
+    let mut chunked = Vec::new();
+    loop {
+        let len = reader.read(&mut chunk)?;
+        if len == 0 {
+            break;
+        }
+        chunked.extend_from_slice(&chunk[..len]);
+    }
+    assert_eq!(chunked, contents);
+
+    let mut empty = Vec::new();
+    root_dir
+        .get_path("EMPTY.BIN")?
+        .reader()?
+        .read_to_end(&mut empty)?;
+    assert!(empty.is_empty());
+    Ok(())
+}
+
+#[test]
+pub fn read_fragmented_chain() -> Result<()> {
+    let contents: Vec<u8> = (0..IMG_CLUSTER_SIZE * 5).map(|x| (x / 7) as u8).collect();
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "FRAG", "BIN", &contents);
+    // On disk as 0,1 -> 4 -> 2,3, so the runs land out of order in the file
+    img.set_fat(start + 1, start + 4);
+    img.set_fat(start + 4, start + 2);
+    img.set_fat(start + 3, 0xffff);
+    let (vff, _) = VFF::new(img.build())?;
+
+    let mut vff = vff.borrow_mut();
+    let mut expected = Vec::new();
+    for cluster in [0, 1, 4, 2, 3] {
+        expected.extend(vff.read_cluster((start + cluster).into())?);
+    }
+    assert_eq!(vff.read_chain(start.into())?, expected);
+    assert_eq!(
+        &expected[IMG_CLUSTER_SIZE * 2..IMG_CLUSTER_SIZE * 3],
+        &contents[IMG_CLUSTER_SIZE * 4..]
+    );
+    Ok(())
+}
+
+#[test]
+pub fn defragment() -> Result<()> {
+    let contents: Vec<u8> = (0..IMG_CLUSTER_SIZE * 3).map(|x| (x / 5) as u8).collect();
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "FRAG", "BIN", &contents);
+    // On disk as 0 -> 2 -> 1, with something in the way of moving it up
+    img.set_fat(start, start + 2);
+    img.set_fat(start + 2, start + 1);
+    img.set_fat(start + 1, 0xffff);
+    let blocker = img.add_file(None, "NEXT", "BIN", b"next");
+    img.add_file(None, "CONT", "BIN", &[1; IMG_CLUSTER_SIZE * 2]);
+    img.add_file(None, "EMPTY", "BIN", &[]);
+    let (vff, _) = VffOptions::new().open_writable(img.build())?;
+
+    let read = |path: &str| {
+        VFF::list_dir(&vff, "", false)
+            .map(|entries| entries.into_iter().find(|e| e.full_name() == path).unwrap())
+    };
+    let before = read("FRAG.BIN")?.load_bytes()?;
+    assert_eq!(vff.borrow_mut().defragment("FRAG.BIN")?, 3);
+    let moved = read("FRAG.BIN")?;
+    assert_eq!(moved.load_bytes()?, before);
+    let new_start: u32 = (blocker + 1 + 2).into();
+    {
+        let vff = vff.borrow();
+        let fat = vff.fat()?;
+        assert_eq!(
+            fat.get_chain(new_start)?,
+            [new_start, new_start + 1, new_start + 2]
+        );
+        for old in start..start + 3 {
+            assert_eq!(fat.get_cluster(old.into())?, 0);
+        }
+    }
+    assert!(vff.borrow().verify_fats()?.is_empty());
+    assert_eq!(vff.borrow().fragmentation(new_start)?, 1);
+
+    assert_eq!(vff.borrow_mut().defragment("CONT.BIN")?, 1);
+    assert_eq!(vff.borrow_mut().defragment("EMPTY.BIN")?, 0);
+    assert!(vff.borrow().verify_fats()?.is_empty());
+    Ok(())
+}
+
+#[test]
+pub fn fragmentation() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let contiguous = img.add_file(None, "CONT", "BIN", &[1; IMG_CLUSTER_SIZE * 3]);
+    let fragmented = img.add_file(None, "FRAG", "BIN", &[2; IMG_CLUSTER_SIZE * 4]);
+    // Split into 0,1 -> 3 -> 2
+    img.set_fat(fragmented + 1, fragmented + 3);
+    img.set_fat(fragmented + 3, fragmented + 2);
+    img.set_fat(fragmented + 2, 0xffff);
+    img.add_file(None, "EMPTY", "BIN", &[]);
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    let empty = root_dir.get_path("EMPTY.BIN")?;
+    let vff = vff.borrow();
+    assert_eq!(vff.fragmentation(contiguous.into())?, 1);
+    assert_eq!(vff.fragmentation(fragmented.into())?, 3);
+    assert_eq!(vff.fragmentation(empty.start_cluster().unwrap())?, 0);
+
+    assert_eq!(vff.chain_len(fragmented.into())?, 4);
+    assert_eq!(vff.chain_len(0)?, 0);
+    assert_eq!(
+        vff.allocated_size(contiguous.into())?,
+        IMG_CLUSTER_SIZE as u64 * 3
+    );
+    Ok(())
+}
+
+#[test]
+pub fn read_slack() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "OLD", "BIN", &[0xaa; IMG_CLUSTER_SIZE * 2]);
+    // Shrink the entry so the tail of the old data is left as slack
+    img.data[IMG_ROOT_OFFSET + 28..IMG_ROOT_OFFSET + 32]
+        .copy_from_slice(&(IMG_CLUSTER_SIZE as u32 + 0x10).to_le_bytes());
+    let full = img.add_file(None, "FULL", "BIN", &[1; IMG_CLUSTER_SIZE]);
+    let (vff, _) = VFF::new(img.build())?;
+
+    let vff = vff.borrow();
+    let slack = vff.read_slack(start.into(), IMG_CLUSTER_SIZE as u32 + 0x10)?;
+    assert_eq!(slack, [0xaa; IMG_CLUSTER_SIZE - 0x10]);
+    assert!(vff
+        .read_slack(full.into(), IMG_CLUSTER_SIZE as u32)?
+        .is_empty());
+    assert!(vff.read_slack(0, 0)?.is_empty());
+    // A size past the end of the chain
+    assert!(vff
+        .read_slack(full.into(), IMG_CLUSTER_SIZE as u32 * 2 + 1)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+pub fn file_reader_seek() -> Result<()> {
+    let contents: Vec<u8> = (0..IMG_CLUSTER_SIZE * 5).map(|x| (x / 3) as u8).collect();
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "BIG", "BIN", &contents);
+    // Out of order so the seek has to follow the links
+    let order = [0, 3, 1, 4, 2];
+    for pair in order.windows(2) {
+        img.set_fat(start + pair[0], start + pair[1]);
+    }
+    img.set_fat(start + 2, 0xffff);
+    let expected: Vec<u8> = order
+        .iter()
+        .flat_map(|&i| contents.chunks(IMG_CLUSTER_SIZE).nth(i as usize).unwrap())
+        .copied()
+        .collect();
+    let (_, root_dir) = VFF::new(img.build())?;
+    let mut reader = root_dir.get_path("BIG.BIN")?.reader()?;
+
+    let mut buf = [0u8; 0x10];
+    assert_eq!(reader.seek(io::SeekFrom::Start(0x410))?, 0x410);
+    reader.read_exact(&mut buf)?;
+    assert_eq!(buf, expected[0x410..0x420]);
+    assert_eq!(reader.stream_position()?, 0x420);
+
+    // Straddle a cluster boundary
+    reader.seek(io::SeekFrom::Current(-0x228))?;
+    reader.read_exact(&mut buf)?;
+    assert_eq!(buf, expected[0x1f8..0x208]);
+
+    let mut tail = Vec::new();
+    reader.seek(io::SeekFrom::End(-0x10))?;
+    reader.read_to_end(&mut tail)?;
+    assert_eq!(tail, expected[expected.len() - 0x10..]);
+
+    assert_eq!(
+        reader.seek(io::SeekFrom::End(0x10))?,
+        expected.len() as u64 + 0x10
+    );
+    assert_eq!(reader.read(&mut buf)?, 0);
+    assert!(reader.seek(io::SeekFrom::Current(-0x10000)).is_err());
+
+    reader.rewind()?;
+    let mut all = Vec::new();
+    reader.read_to_end(&mut all)?;
+    assert_eq!(all, expected);
+    Ok(())
+}
+
+#[test]
+pub fn batched_chain_reads() -> Result<()> {
+    let contents: Vec<u8> = (0..IMG_CLUSTER_SIZE * 6).map(|x| (x / 7) as u8).collect();
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "FRAG", "BIN", &contents);
+    // Fragment the chain into 2,3 -> 6 -> 4,5 -> 7
+    let order = [start, start + 1, start + 4, start + 2, start + 3, start + 5];
+    for pair in order.windows(2) {
+        img.set_fat(pair[0], pair[1]);
+    }
+    img.set_fat(order[5], 0xffff);
+    let (vff, _) = VFF::new(img.build())?;
+    let mut vff = vff.borrow_mut();
+
+    let chain = vff.fat()?.get_chain(start.into())?;
+    let runs: Vec<&[u32]> = cluster_runs(&chain).collect();
+    assert_eq!(runs, [&[2, 3][..], &[6], &[4, 5], &[7]]);
+    let mut per_cluster = Vec::new();
+    for cluster in &chain {
+        per_cluster.extend(vff.read_cluster(*cluster)?);
+    }
+    assert_eq!(vff.read_chain(start.into())?, per_cluster);
+    Ok(())
+}
+
+#[test]
+pub fn chain_cache() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "A", "BIN", &[3; 0x600]);
+    let (vff, _) = VFF::new(img.build())?;
+    let mut vff = vff.borrow_mut();
+    let first = vff.read_chain(start.into())?;
+    assert_eq!(&*vff.chain_cache[&start.into()], &[2, 3, 4]);
+    // Repeated reads come from the cache rather than the table
+    vff.fats_mut()?.primary.clusters[start as usize] = 0;
+    assert_eq!(vff.read_chain(start.into())?, first);
+    Ok(())
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+pub fn from_mmap() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "CDB~1", "CON", b"conf");
+    let mut path = std::env::temp_dir();
+    path.push("WiiVFF-tests-mmap.vff");
+    std::fs::write(&path, &img.data)?;
+    let (_, root_dir) = VFF::from_mmap(&path)?;
+    assert_eq!(root_dir.get_path("CDB~1.CON")?.load_bytes()?, b"conf");
+    drop(root_dir);
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+pub fn dump_parallel() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "LOGS");
+    for i in 0..12u8 {
+        img.add_file(
+            Some(dir),
+            &format!("LOG{i}"),
+            "BIN",
+            &vec![i; 0x180 * i as usize],
+        );
+    }
+    let mut base = std::env::temp_dir();
+    base.push("WiiVFF-tests-parallel");
+    if base.exists() {
+        std::fs::remove_dir_all(&base)?;
+    }
+    std::fs::create_dir_all(&base)?;
+    let source = base.join("cdb.vff");
+    std::fs::write(&source, &img.data)?;
+    let (_, root_dir) = VFF::from_path(&source)?;
+    root_dir.dump_parallel(&source, base.join("out"), false)?;
+    for i in 0..12u8 {
+        let dumped = std::fs::read(base.join(format!("out/LOGS/LOG{i}.BIN")))?;
+        assert_eq!(dumped, vec![i; 0x180 * i as usize]);
+    }
+
+    // An intact chain that's shorter than the file fails as it would in `dump`
+    let mut img = ImageBuilder::new();
+    let start = img.alloc(&[7; IMG_CLUSTER_SIZE]);
+    img.add_raw(
+        None,
+        ImageBuilder::raw_entry("SHORT", "BIN", 0x20, start, IMG_CLUSTER_SIZE as u32 * 2),
+    );
+    let source = base.join("short.vff");
+    std::fs::write(&source, &img.data)?;
+    let (vff, root_dir) = VFF::from_path(&source)?;
+    let Err(VFFError::InvalidData { context, .. }) =
+        root_dir.dump_parallel(&source, base.join("short"), false)
+    else {
+        panic!("a chain shorter than its file should fail the dump");
+    };
+    assert_eq!(context, "VFF::file_extents");
+    vff.borrow_mut().set_lenient(true);
+    root_dir.dump_parallel(&source, base.join("short"), false)?;
+    assert_eq!(
+        std::fs::read(base.join("short/SHORT.BIN"))?,
+        [7; IMG_CLUSTER_SIZE]
+    );
+    assert_eq!(
+        vff.borrow().warnings(),
+        [Warning::SizeMismatch {
+            start: start.into(),
+            size: IMG_CLUSTER_SIZE as u32 * 2,
+            clusters: 1
+        }]
+    );
+    std::fs::remove_dir_all(&base)?;
+    Ok(())
+}
+
+#[test]
+pub fn unix_timestamps() {
+    let time = FatDateTime::from_raw((42 << 9) | (10 << 5) | 15, (21 << 11) | (44 << 5) | 15);
+    assert_eq!(time.unwrap().to_unix_timestamp(), 1665870270);
+    let epoch = FatDateTime::from_raw((1 << 5) | 1, 0);
+    assert_eq!(epoch.unwrap().to_unix_timestamp(), 315532800);
+}
+
+#[cfg(feature = "tar")]
+#[test]
+pub fn export_tar() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let year = img.add_dir(None, "2022");
+    let mut entry = ImageBuilder::raw_entry("PLAY", "LOG", 0x20, img.alloc(b"hello"), 5);
+    entry[24..26].copy_from_slice(&((42 << 9) | (10 << 5) | 15u16).to_le_bytes());
+    img.add_raw(Some(year), entry);
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let mut archive = Vec::new();
+    root_dir.export_tar(&mut archive, false)?;
+    let mut archive = tar::Archive::new(archive.as_slice());
+    let mut seen = Vec::new();
+    for file in archive.entries()? {
+        let mut file = file?;
+        let path = file.path()?.to_string_lossy().into_owned();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        seen.push((path, file.header().mtime()?, contents));
+    }
+    assert_eq!(seen.len(), 3);
+    assert_eq!(seen[0].0, "2022");
+    assert_eq!(
+        seen[1],
+        ("2022/PLAY.LOG".to_owned(), 1665792000, b"hello".to_vec())
+    );
+    assert_eq!(seen[2].0, "CDB~1.CON");
+
+    let mut archive = Vec::new();
+    let glob = Glob::new("*.LOG");
+    root_dir.export_tar_matching(&mut archive, false, &|e| glob.matches_entry(e))?;
+    let mut archive = tar::Archive::new(archive.as_slice());
+    let paths: Vec<String> = archive
+        .entries()?
+        .map(|file| Ok(file?.path()?.to_string_lossy().into_owned()))
+        .collect::<Result<_>>()?;
+    assert_eq!(paths, ["2022/PLAY.LOG"]);
+    Ok(())
+}
+
+#[cfg(feature = "zip")]
+#[test]
+pub fn export_zip() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let year = img.add_dir(None, "2022");
+    let mut entry = ImageBuilder::raw_entry("PLAY", "LOG", 0x20, img.alloc(b"hello"), 5);
+    entry[24..26].copy_from_slice(&((42 << 9) | (10 << 5) | 15u16).to_le_bytes());
+    img.add_raw(Some(year), entry);
+    let mut gone = ImageBuilder::raw_entry("GONE", "TXT", 0x20, img.alloc(b"bye"), 3);
+    gone[0] = 0xE5;
+    img.add_raw(None, gone);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let mut archive = io::Cursor::new(Vec::new());
+    root_dir.export_zip(&mut archive, true)?;
+    let mut archive = zip::ZipArchive::new(archive).map_err(io::Error::from)?;
+    let names: Vec<_> = archive
+        .file_names()
+        .map(|n| n.unwrap().into_owned())
+        .collect();
+    assert_eq!(names.len(), 3);
+    assert!(names.iter().any(|n| n.ends_with("ONE.TXT.deleted")));
+    let mut file = archive.by_name("2022/PLAY.LOG").map_err(io::Error::from)?;
+    let modified = file.last_modified().unwrap();
+    assert_eq!(
+        (modified.year(), modified.month(), modified.day()),
+        (2022, 10, 15)
+    );
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    assert_eq!(contents, b"hello");
+    drop(file);
+    assert!(archive.by_name("2022/").is_ok());
+    Ok(())
+}
+
+#[test]
+pub fn dump_preserves_mtime() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let mut entry = ImageBuilder::raw_entry("PLAY", "LOG", 0x20, img.alloc(b"hello"), 5);
+    entry[22..24].copy_from_slice(&((21 << 11) | (44 << 5) | 15u16).to_le_bytes());
+    entry[24..26].copy_from_slice(&((42 << 9) | (10 << 5) | 15u16).to_le_bytes());
+    img.add_raw(None, entry);
+    img.add_file(None, "NOTES", "TXT", b"no date");
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-mtime");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    root_dir.dump(test_dir.clone(), false)?;
+    let modified = std::fs::metadata(test_dir.join("PLAY.LOG"))?.modified()?;
+    assert_eq!(modified, UNIX_EPOCH + Duration::from_secs(1665870270));
+    // Unset timestamps leave whatever the OS picked
+    let modified = std::fs::metadata(test_dir.join("NOTES.TXT"))?.modified()?;
+    assert!(modified > UNIX_EPOCH + Duration::from_secs(1665870270));
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn tree() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let year = img.add_dir(None, "2022");
+    let month = img.add_dir(Some(year), "10");
+    img.add_file(Some(month), "2B06C4C3", "000", &[7; 0x20]);
+    img.add_file(Some(month), "PLAY", "LOG", b"hello");
+    img.add_dir(Some(year), "11");
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    assert_eq!(
+        root_dir.tree(false, &|_| true)?,
+        [
+            "/",
+            "├── 2022/",
+            "│   ├── 10/",
+            "│   │   ├── 2B06C4C3.000 [0x0020]",
+            "│   │   └── PLAY.LOG [0x0005]",
+            "│   └── 11/",
+            "└── CDB~1.CON [0x0004]",
+        ]
+    );
+    let glob = Glob::new("*.LOG");
+    assert_eq!(
+        root_dir.tree(false, &|entry| glob.matches_entry(entry))?,
+        [
+            "/",
+            "└── 2022/",
+            "    └── 10/",
+            "        └── PLAY.LOG [0x0005]"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+pub fn display_directory() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_file(Some(dir), "PLAY", "LOG", b"hello");
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let (_, root_dir) = VFF::new(img.build())?;
+    assert_eq!(
+        root_dir.to_string(),
+        "/2022/PLAY.LOG [0x0005]\n/CDB~1.CON [0x0004]"
+    );
+
+    // A directory whose chain starts on a free cluster
+    let mut img = ImageBuilder::new();
+    img.add_raw(None, ImageBuilder::raw_entry("BROKEN", "", 0x10, 0x200, 0));
+    let (_, root_dir) = VFF::new(img.build())?;
+    let e = root_dir.ls(false).unwrap_err();
+    assert_eq!(root_dir.to_string(), format!("<unreadable: {e}>"));
+    Ok(())
+}
+
+#[test]
+pub fn raw_entries() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    let start = img.add_file(Some(dir), "PLAY", "LOG", b"hello");
+    let (_, root_dir) = VFF::new(img.build())?;
+    let year = root_dir.get_path("2022")?;
+    let raw = year.dir().unwrap().raw_entries(false)?;
+    let names: Vec<String> = raw.iter().map(|e| e.nice_short_name()).collect();
+    assert_eq!(names, [".", "..", "PLAY.LOG"]);
+    assert_eq!(raw[2].start, start);
+    assert_eq!(raw[2].size, 5);
+    assert_eq!(raw[2].attr, 0x20);
+    Ok(())
+}
+
+#[test]
+pub fn ls_entries() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    let play_start = img.add_file(Some(dir), "PLAY", "LOG", b"hello");
+    img.add_dir(None, "EMPTY");
+    let mut entry = ImageBuilder::raw_entry("GONE", "TXT", 0x20, 0, 3);
+    entry[0] = 0xe5;
+    img.add_raw(None, entry);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let entries = root_dir.ls_entries(true)?;
+    let starts: Vec<_> = entries.iter().map(|e| e.start).collect();
+    let empty = root_dir.get_path("EMPTY")?;
+    assert_eq!(starts[0], Some(play_start as u32));
+    assert_eq!(starts[1], empty.start_cluster());
+    assert_eq!(starts[2], Some(0));
+    assert_eq!(empty.dir().unwrap().ls_entries(false)?[0].start, starts[1]);
+    let plain: Vec<_> = entries
+        .iter()
+        .map(|e| (e.path.as_str(), e.size, e.deleted))
+        .collect();
+    assert_eq!(
+        plain,
+        [
+            ("/2022/PLAY.LOG", Some(5), false),
+            ("/EMPTY", None, false),
+            ("/\u{fffd}ONE.TXT", Some(3), true),
+        ]
+    );
+    assert_eq!(root_dir.ls(true)?[2], "/\u{fffd}ONE.TXT [0x0003] [DELETED]");
+
+    Ok(())
+}
+
+#[test]
+pub fn empty_vff() -> Result<()> {
+    let (vff, root_dir) = VFF::new(ImageBuilder::new().build())?;
+    assert!(root_dir.ls(false)?.is_empty());
+    assert!(root_dir.ls(true)?.is_empty());
+    assert!(root_dir.ls_entries(true)?.is_empty());
+    assert!(root_dir.ls_matching(false, &|_| true)?.is_empty());
+    assert!(root_dir.paths(true)?.is_empty());
+    assert!(root_dir.list_detailed(true)?.is_empty());
+    assert_eq!(root_dir.tree(false, &|_| true)?, ["/"]);
+    assert!(vff.borrow().warnings().is_empty());
+
+    // Deleted entries don't make the root any less empty when they aren't shown
+    let mut img = ImageBuilder::new();
+    let mut entry = ImageBuilder::raw_entry("GONE", "TXT", 0x20, 0, 3);
+    entry[0] = 0xe5;
+    img.add_raw(None, entry);
+    let (_, root_dir) = VFF::new(img.build())?;
+    assert!(root_dir.ls(false)?.is_empty());
+    assert_eq!(root_dir.ls(true)?, ["/\u{fffd}ONE.TXT [0x0003] [DELETED]"]);
+    Ok(())
+}
+
+#[test]
+pub fn glob_filter() -> Result<()> {
+    let glob = Glob::new("*.log");
+    assert!(glob.matches("/2022/10/PLAY.LOG"));
+    assert!(glob.matches("/PLAY.LOG"));
+    assert!(!glob.matches("/2022/10/PLAY.LOGX"));
+    let glob = Glob::new("/2022/1?/*");
+    assert!(glob.matches("/2022/10/2B06C4C3.000"));
+    assert!(!glob.matches("/2022/10"));
+    assert!(!glob.matches("/2022/100/A"));
+    assert!(!glob.matches("/2022/10/sub/A"));
+    let glob = Glob::new("2022/**/*.000");
+    assert!(glob.matches("/2022/10/2B06C4C3.000"));
+    assert!(glob.matches("/2022/2B06C4C3.000"));
+    assert!(!glob.matches("/2023/10/2B06C4C3.000"));
+
+    let mut img = ImageBuilder::new();
+    let year = img.add_dir(None, "2022");
+    let month = img.add_dir(Some(year), "10");
+    img.add_file(Some(month), "2B06C4C3", "000", &[7; 0x20]);
+    img.add_file(Some(month), "PLAY", "LOG", b"hello");
+    img.add_dir(Some(year), "11");
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let glob = Glob::new("*.LOG");
+    assert_eq!(
+        root_dir.ls_matching(false, &|entry| glob.matches_entry(entry))?,
+        ["/2022/10/PLAY.LOG [0x0005]"]
+    );
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-filter");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    root_dir.dump_matching(test_dir.clone(), false, &|entry| glob.matches_entry(entry))?;
+    assert_eq!(std::fs::read(test_dir.join("2022/10/PLAY.LOG"))?, b"hello");
+    assert!(!test_dir.join("2022/10/2B06C4C3.000").exists());
+    assert!(!test_dir.join("2022/11").exists());
+    assert!(!test_dir.join("CDB~1.CON").exists());
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn lenient_reads() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_file(Some(dir), "NOTES", "TXT", b"notes");
+    // Two clusters, where the second one is marked bad
+    let bad = img.add_file(None, "BAD", "BIN", &[1; IMG_CLUSTER_SIZE * 2]);
+    img.set_fat(bad + 1, 0xfff7);
+    // Two clusters, where the first links to a free cluster
+    let cut = img.add_file(None, "CUT", "BIN", &[2; IMG_CLUSTER_SIZE * 2]);
+    img.set_fat(cut, 0);
+    // The directory chain links to a free cluster too
+    img.set_fat(dir, 0);
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    assert!(vff.borrow_mut().read_chain(bad.into()).is_err());
+    assert_eq!(
+        vff.borrow_mut().read_chain_lenient(bad.into())?,
+        [1; IMG_CLUSTER_SIZE]
+    );
+    assert_eq!(
+        vff.borrow_mut().take_warnings(),
+        [Warning::BadCluster {
+            start: bad.into(),
+            cluster: (bad + 1).into()
+        }]
+    );
+    assert!(root_dir.entries(false, false).is_err());
+
+    vff.borrow_mut().set_lenient(true);
+    let entries = root_dir.entries(false, false)?;
+    assert_eq!(
+        entries[0].dir().unwrap().ls(false)?,
+        ["/2022/NOTES.TXT [0x0005]"]
+    );
+    assert_eq!(entries[1].load_bytes()?, [1; IMG_CLUSTER_SIZE]);
+    let mut cut_data = Vec::new();
+    entries[2].reader()?.read_to_end(&mut cut_data)?;
+    assert_eq!(cut_data, [2; IMG_CLUSTER_SIZE]);
+    assert_eq!(
+        vff.borrow().warnings(),
+        [
+            Warning::InvalidTerminator {
+                start: dir.into(),
+                cluster: dir.into(),
+                value: 0
+            },
+            Warning::BadCluster {
+                start: bad.into(),
+                cluster: (bad + 1).into()
+            },
+            Warning::InvalidTerminator {
+                start: cut.into(),
+                cluster: cut.into(),
+                value: 0
+            },
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+pub fn find_orphans() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_file(Some(dir), "NOTES", "TXT", b"notes");
+    img.add_file(None, "KEEP", "BIN", &[1; IMG_CLUSTER_SIZE * 2]);
+    // Allocated in the FAT, but no entry points at it
+    let lost = img.alloc(&[2; IMG_CLUSTER_SIZE * 3]);
+    // A deleted file whose clusters were never freed
+    let gone = img.alloc(b"gone");
+    let mut entry = ImageBuilder::raw_entry("GONE", "TXT", 0x20, gone, 4);
+    entry[0] = 0xe5;
+    img.add_raw(None, entry);
+    // A loop with no way in
+    let looped = img.alloc(&[3; IMG_CLUSTER_SIZE * 2]);
+    img.set_fat(looped + 1, looped);
+    // Bad clusters aren't lost space
+    img.set_fat(0x100, 0xfff7);
+    let (vff, _) = VFF::new(img.build())?;
+
+    let lost: u32 = lost.into();
+    let (gone, looped): (u32, u32) = (gone.into(), looped.into());
+    assert_eq!(
+        vff.borrow().find_orphans()?,
+        [
+            vec![lost, lost + 1, lost + 2],
+            vec![gone],
+            vec![looped, looped + 1]
+        ]
+    );
+    assert_eq!(vff.borrow().unreferenced_used_clusters()?, 6);
+    Ok(())
+}
+
+#[test]
+pub fn find_crosslinks() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    let notes = img.add_file(Some(dir), "NOTES", "TXT", &[1; IMG_CLUSTER_SIZE * 2]);
+    let play = img.add_file(None, "PLAY", "LOG", &[2; IMG_CLUSTER_SIZE * 2]);
+    img.add_file(None, "KEEP", "BIN", b"fine");
+    // PLAY.LOG's first cluster now leads into NOTES.TXT
+    img.set_fat(play, notes);
+    let (vff, _) = VFF::new(img.build())?;
+
+    let first_note: u32 = notes.into();
+    // The root is walked before its subdirectories, so PLAY.LOG claims the clusters first
+    let (play, notes) = ("/PLAY.LOG".to_owned(), "/2022/NOTES.TXT".to_owned());
+    assert_eq!(
+        vff.borrow().find_crosslinks()?,
+        [
+            (play.clone(), notes.clone(), first_note),
+            (play, notes, first_note + 1)
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+pub fn check_chains() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "GOOD", "BIN", &[1; IMG_CLUSTER_SIZE + 1]);
+    img.add_file(None, "EMPTY", "TXT", b"");
+    let looped = img.add_file(None, "LOOP", "BIN", &[2; IMG_CLUSTER_SIZE * 3]);
+    img.set_fat(looped + 2, looped + 1);
+    // Claims to be a single cluster, but owns two
+    let start = img.alloc(&[3; IMG_CLUSTER_SIZE * 2]);
+    img.add_raw(
+        None,
+        ImageBuilder::raw_entry("SHORT", "BIN", 0x20, start, 4),
+    );
+    let (vff, _) = VFF::new(img.build())?;
+
+    let looped: u32 = looped.into();
+    assert!(vff.borrow().fat()?.get_chain(looped).is_err());
+    assert_eq!(
+        vff.borrow().fat()?.get_chain_lenient(looped),
+        (
+            vec![looped, looped + 1, looped + 2],
+            Some(Warning::Loop {
+                start: looped,
+                cluster: looped + 2
+            })
+        )
+    );
+    assert_eq!(
+        vff.borrow().check_chains()?,
+        [
+            (
+                "/LOOP.BIN".to_owned(),
+                Warning::Loop {
+                    start: looped,
+                    cluster: looped + 2
+                }
+            ),
+            (
+                "/SHORT.BIN".to_owned(),
+                Warning::SizeMismatch {
+                    start: start.into(),
+                    size: 4,
+                    clusters: 2
+                }
+            )
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+pub fn size_past_end_of_chain() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    // One cluster, but the entry claims two
+    let start = img.alloc(&[4; IMG_CLUSTER_SIZE]);
+    let size = (IMG_CLUSTER_SIZE * 2) as u32;
+    img.add_raw(
+        None,
+        ImageBuilder::raw_entry("LONG", "BIN", 0x20, start, size),
+    );
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    assert!(root_dir.get_path("LONG.BIN").is_err());
+    let entry = &root_dir.entries(false, false)?[0];
+    assert!(entry.load_bytes().is_err());
+    assert!(io::copy(&mut entry.reader()?, &mut io::sink()).is_err());
+
+    vff.borrow_mut().set_lenient(true);
+    assert_eq!(entry.load_bytes()?, [4; IMG_CLUSTER_SIZE]);
+    let mut streamed = Vec::new();
+    entry.reader()?.read_to_end(&mut streamed)?;
+    assert_eq!(streamed, [4; IMG_CLUSTER_SIZE]);
+    let warning = Warning::SizeMismatch {
+        start: start.into(),
+        size,
+        clusters: 1,
+    };
+    assert_eq!(vff.borrow().warnings(), [warning.clone(), warning]);
+    Ok(())
+}
+
+#[test]
+pub fn kanji_escape() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let mut escaped = ImageBuilder::raw_entry("\x05ABC", "TXT", 0x20, img.alloc(b"kanji"), 5);
+    img.add_raw(None, escaped);
+    escaped[0] = 0xe5;
+    img.add_raw(None, escaped);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let live = root_dir.read(false)?;
+    assert_eq!(live.len(), 1);
+    assert_eq!(&live[0].name_bytes()[..4], [0xe5, b'A', b'B', b'C']);
+    assert_eq!(live[0].nice_name(), "\u{fffd}ABC");
+    assert!(!root_dir.entries(false, false)?[0].is_deleted());
+    let all = root_dir.read(true)?;
+    assert!(!all[0].deleted && all[1].deleted);
+    Ok(())
+}
+
+#[cfg(feature = "encoding_rs")]
+#[test]
+pub fn shift_jis_names() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    // 日記.TXT
+    let mut diary = ImageBuilder::raw_entry("", "TXT", 0x20, img.alloc(b"diary"), 5);
+    diary[0..4].copy_from_slice(&[0x93, 0xfa, 0x8b, 0x4c]);
+    img.add_raw(None, diary);
+    let data = img.build().into_inner();
+
+    let (_, root_dir) = VFF::new(std::io::Cursor::new(data.clone()))?;
+    assert_eq!(
+        root_dir.read(false)?[0].nice_name(),
+        "\u{fffd}\u{fffd}\u{fffd}L"
+    );
+    let (vff, root_dir) = VffOptions::new()
+        .name_encoding(NameEncoding::ShiftJis)
+        .open(std::io::Cursor::new(data))?;
+    let entries = root_dir.entries(false, false)?;
+    assert_eq!(entries[0].full_name(), "日記.TXT");
+    assert_eq!(root_dir.get_path("日記.txt")?.load_bytes()?, b"diary");
+    assert_eq!(vff.borrow_mut().read_path("/日記.TXT")?, b"diary");
+    Ok(())
+}
+
+#[test]
+pub fn newest_file() -> Result<()> {
+    let with_date = |name, ext, date: u16, time: u16| {
+        let mut entry = ImageBuilder::raw_entry(name, ext, 0x20, 0, 0);
+        entry[22..24].copy_from_slice(&time.to_le_bytes());
+        entry[24..26].copy_from_slice(&date.to_le_bytes());
+        entry
+    };
+    // 2022-10-15, with the time of day deciding between the two newest
+    let date = (42 << 9) | (10 << 5) | 15;
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_raw(Some(dir), with_date("OLD", "LOG", date - 1, 0xffff));
+    img.add_raw(Some(dir), with_date("PLAY", "LOG", date, 21 << 11));
+    img.add_raw(None, with_date("EARLIER", "LOG", date, 20 << 11));
+    img.add_raw(None, with_date("NODATE", "BIN", 0, 0));
+    let mut deleted = with_date("GONE", "LOG", date + 1, 0);
+    deleted[0] = 0xe5;
+    img.add_raw(None, deleted);
+    let (vff, _) = VFF::new(img.build())?;
+
+    let (path, modified) = vff.borrow().newest_file()?.unwrap();
+    assert_eq!(path, "/2022/PLAY.LOG");
+    assert_eq!((modified.year, modified.hour), (2022, 21));
+
+    let (vff, _) = VFF::new(ImageBuilder::new().build())?;
+    assert_eq!(vff.borrow().newest_file()?, None);
+    Ok(())
+}
+
+#[test]
+pub fn deleted_summary() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    let mut entry = ImageBuilder::raw_entry("PLAY", "LOG", 0x20, 0, 0x1234);
+    entry[0] = 0xe5;
+    img.add_raw(Some(dir), entry);
+    let gone = img.add_dir(None, "GONE");
+    img.add_file(Some(gone), "INNER", "BIN", &[1; 4]);
+    let mut entry = ImageBuilder::raw_entry("GONE", "", 0x10, gone, 0);
+    entry[0] = 0xe5;
+    // Replace the live entry for GONE with a deleted one
+    img.data[IMG_ROOT_OFFSET + 32..IMG_ROOT_OFFSET + 64].copy_from_slice(&entry);
+    img.add_file(None, "KEEP", "TXT", b"live");
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    assert_eq!(
+        vff.borrow().deleted_summary()?,
+        [("/2022/\u{fffd}LAY.LOG".to_owned(), 0x1234)]
+    );
+    assert_eq!(root_dir.total_size(false)?, 4);
+    // Like dump, this goes into the deleted directory too
+    assert_eq!(root_dir.total_size(true)?, 4 + 0x1234 + 4);
+    Ok(())
+}
+
+#[test]
+pub fn recover_deleted() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    // Deleting frees the chain and overwrites the first byte of the name
+    let contents: Vec<u8> = (0..IMG_CLUSTER_SIZE * 2 + 3).map(|i| i as u8).collect();
+    let start = img.alloc(&contents);
+    for cluster in start..start + 3 {
+        img.set_fat(cluster, 0);
+    }
+    let mut entry = ImageBuilder::raw_entry("PLAY", "LOG", 0x20, start, contents.len() as u32);
+    entry[0] = 0xe5;
+    img.add_raw(Some(dir), entry);
+    // Only the first cluster is still free, the second now belongs to NEW.BIN
+    let reused = img.alloc(&[1; IMG_CLUSTER_SIZE * 2]);
+    img.set_fat(reused, 0);
+    img.add_raw(
+        None,
+        ImageBuilder::raw_entry("NEW", "BIN", 0x20, reused + 1, IMG_CLUSTER_SIZE as u32),
+    );
+    let mut entry =
+        ImageBuilder::raw_entry("OLD", "BIN", 0x20, reused, IMG_CLUSTER_SIZE as u32 * 2);
+    entry[0] = 0xe5;
+    img.add_raw(None, entry);
+    img.add_file(None, "KEEP", "TXT", b"live");
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-recover");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    root_dir.recover_deleted(test_dir.clone())?;
+    assert_eq!(std::fs::read(test_dir.join("2022/_LAY.LOG"))?, contents);
+    assert_eq!(
+        std::fs::read(test_dir.join("_LD.BIN"))?,
+        [1; IMG_CLUSTER_SIZE]
+    );
+    assert!(!test_dir.join("KEEP.TXT").exists());
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn recover_unsafe_names() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    for (i, long_name) in ["/abs.bin", ".."].into_iter().enumerate() {
+        let start = img.alloc(&[i as u8; 4]);
+        img.set_fat(start, 0);
+        let mut entry = ImageBuilder::raw_entry(&format!("FILE{i}"), "BIN", 0x20, start, 4);
+        for mut slot in ImageBuilder::lfn_slots(long_name, ImageBuilder::checksum(&entry)) {
+            slot[0] = 0xe5;
+            img.add_raw(None, slot);
+        }
+        entry[0] = 0xe5;
+        img.add_raw(None, entry);
+    }
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    let mut test_dir = std::env::temp_dir();
+    test_dir.push("WiiVFF-tests-recover-unsafe-names");
+    if test_dir.exists() {
+        std::fs::remove_dir_all(&test_dir)?;
+    }
+    let out = test_dir.join("out");
+    root_dir.recover_deleted(out.clone())?;
+    assert_eq!(std::fs::read(out.join("_abs.bin"))?, [0; 4]);
+    assert_eq!(std::fs::read(out.join("__"))?, [1; 4]);
+    assert_eq!(std::fs::read_dir(&test_dir)?.count(), 1);
+    assert_eq!(
+        vff.borrow().warnings(),
+        [
+            Warning::UnsafeName {
+                path: "//abs.bin".to_owned(),
+                name: "_abs.bin".to_owned()
+            },
+            Warning::UnsafeName {
+                path: "/..".to_owned(),
+                name: "__".to_owned()
+            }
+        ]
+    );
+    std::fs::remove_dir_all(&test_dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn header_preamble() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.data[4..8].copy_from_slice(&0xfeff0020u32.to_be_bytes());
+    let data = img.build().into_inner();
+    let (vff, _) = VFF::from_bytes(data.clone())?;
+    assert_eq!(vff.borrow().header().unknown, 0xfeff0020);
+
+    // A copy of the header is accepted as well as padding
+    let mut copied = data.clone();
+    copied.copy_within(0..0x10, 0x10);
+    let (vff, _) = VFF::from_bytes(copied.clone())?;
+    assert_eq!(vff.borrow().header().raw_preamble2[..], copied[..0x10]);
+    let mut garbage = data;
+    garbage[0x18] = 1;
+    assert!(matches!(
+        VFF::from_bytes(garbage.clone()),
+        Err(VFFError::InvalidData { .. })
+    ));
+    // Something that isn't a VFF at all is reported as such, not as a bad preamble
+    let mut not_vff = garbage.clone();
+    not_vff[..4].copy_from_slice(b"NOPE");
+    let Err(VFFError::InvalidData { context, .. }) = VFF::from_bytes(not_vff) else {
+        panic!("a file without the magic should be refused");
+    };
+    assert_eq!(context, "Check VFF Header: parsing file magic");
+    let options = VffOptions::new().validate_preamble(false);
+    let (vff, _) = options.open(std::io::Cursor::new(garbage))?;
+    assert_eq!(vff.borrow().header().raw_preamble2[8], 1);
+    Ok(())
+}
+
+#[test]
+pub fn volume_label() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_raw(None, ImageBuilder::raw_entry("WII CDB", "VFF", 0x08, 0, 0));
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let (vff, root_dir) = VFF::new(img.build())?;
+    assert_eq!(vff.borrow().volume_label()?.as_deref(), Some("WII CDB VFF"));
+    assert_eq!(root_dir.ls(false)?, ["/CDB~1.CON [0x0004]"]);
+
+    let (vff, _) = VFF::new(ImageBuilder::new().build())?;
+    assert_eq!(vff.borrow().volume_label()?, None);
+    Ok(())
+}
+
+#[test]
+pub fn partial_cluster() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "CDB~1", "CON", &[9; 4]);
+    let volume_size = (IMG_CLUSTER_SIZE * IMG_CLUSTER_COUNT + 0x80) as u32;
+    img.data[8..12].copy_from_slice(&volume_size.to_be_bytes());
+    let (vff, root_dir) = VFF::new(img.build())?;
+
+    let vff = vff.borrow();
+    assert_eq!(vff.header().cluster_count, IMG_CLUSTER_COUNT as u32);
+    assert_eq!(
+        vff.warnings(),
+        [Warning::PartialCluster {
+            volume_size,
+            cluster_size: IMG_CLUSTER_SIZE as u16
+        }]
+    );
+    assert!(vff.warnings()[0]
+        .to_string()
+        .ends_with("the last 0x80 bytes"));
+    drop(vff);
+    assert_eq!(root_dir.get_path("CDB~1.CON")?.load_bytes()?, [9; 4]);
+    Ok(())
+}
+
+#[test]
+pub fn header_endianness() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "CDB~1", "CON", &[9; 4]);
+    let big = img.build();
+    let volume_size = (IMG_CLUSTER_SIZE * IMG_CLUSTER_COUNT) as u32;
+    let mut little = big.clone().into_inner();
+    little[8..12].copy_from_slice(&volume_size.to_le_bytes());
+    little[12..14].copy_from_slice(&((IMG_CLUSTER_SIZE / 16) as u16).to_le_bytes());
+    let little = std::io::Cursor::new(little);
+
+    assert_eq!(VffOptions::new().header_endianness, Endianness::Big);
+    let (vff, _) = VFF::new(big.clone())?;
+    assert_eq!(vff.borrow().header().volume_size, volume_size);
+    assert!(VFF::new(little.clone()).is_err());
+
+    let options = VffOptions::new().header_endianness(Endianness::Little);
+    let (vff, root_dir) = options.open(little)?;
+    assert_eq!(vff.borrow().header().volume_size, volume_size);
+    assert_eq!(vff.borrow().header().cluster_size, IMG_CLUSTER_SIZE as u16);
+    assert_eq!(root_dir.get_path("CDB~1.CON")?.load_bytes()?, [9; 4]);
+    assert!(options.open(big).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn default_cluster_size_multiplier() -> Result<()> {
+    let options = VffOptions::new().cluster_size_multiplier(DEFAULT_CLUSTER_SIZE_MULTIPLIER);
+    let (vff, _) = options.open(open()?)?;
+    assert_eq!(vff.borrow().header().cluster_size, 0x200);
+    let (vff, _) = VFF::new(open()?)?;
+    assert_eq!(vff.borrow().header().cluster_size, 0x200);
+    Ok(())
+}
+
+#[test]
+pub fn cluster_size_multiplier() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "CDB~1", "CON", &[9; 4]);
+    // The same geometry, with the header counting in 32 byte units
+    let mut data = img.build().into_inner();
+    data[12..14].copy_from_slice(&((IMG_CLUSTER_SIZE / 32) as u16).to_be_bytes());
+    let data = std::io::Cursor::new(data);
+
+    assert_eq!(VffOptions::new().cluster_size_multiplier, 16);
+    let (vff, root_dir) = VffOptions::new()
+        .cluster_size_multiplier(32)
+        .open(data.clone())?;
+    assert_eq!(vff.borrow().header().cluster_size, IMG_CLUSTER_SIZE as u16);
+    assert_eq!(root_dir.get_path("CDB~1.CON")?.load_bytes()?, [9; 4]);
+    for multiplier in [0, 24] {
+        let options = VffOptions::new().cluster_size_multiplier(multiplier);
+        let Err(VFFError::InvalidData { context, .. }) = options.open(data.clone()) else {
+            panic!("a multiplier of {multiplier} should be refused");
+        };
+        assert_eq!(context, "Opening the VFF: the cluster size multiplier");
+    }
+    // One byte clusters over a 4 GiB volume is far too many for FAT16, which is refused
+    // before it's used to work out the size of the FAT
+    let mut huge = ImageBuilder::new().build().into_inner();
+    huge[8..12].copy_from_slice(&0xffffffffu32.to_be_bytes());
+    huge[12..14].copy_from_slice(&1u16.to_be_bytes());
+    let options = VffOptions::new().cluster_size_multiplier(1);
+    let Err(VFFError::Other(message)) = options.open(std::io::Cursor::new(huge)) else {
+        panic!("a cluster count past FAT16 should be refused");
+    };
+    assert_eq!(message, "FAT 32 is not supported");
+    Ok(())
+}
+
+#[test]
+pub fn cluster_size_power_of_two() -> Result<()> {
+    let mut data = ImageBuilder::new().build().into_inner();
+    // 0x18 * 16 = 0x180
+    data[12..14].copy_from_slice(&0x18u16.to_be_bytes());
+    let Err(VFFError::InvalidData {
+        expected, found, ..
+    }) = VFF::new(std::io::Cursor::new(data))
+    else {
+        panic!("a cluster size of 0x180 should be refused");
+    };
+    assert_eq!(expected, "Cluster size is a power of two");
+    assert_eq!(found, "0x180");
+    Ok(())
+}
+
+#[test]
+pub fn reserved_fat_entries() -> Result<()> {
+    // A dirty shutdown flag in entry 1 is fine
+    let mut img = ImageBuilder::new();
+    img.set_fat(1, 0x7fff);
+    VFF::new(img.build())?;
+
+    let mut img = ImageBuilder::new();
+    img.data[IMG_FAT_OFFSET + IMG_FAT_SIZE..IMG_FAT_OFFSET + IMG_FAT_SIZE + 4].fill(0);
+    let image = img.build();
+    let Err(VFFError::InvalidData { offset, .. }) = VFF::new(image.clone()) else {
+        panic!("a blank backup FAT should be rejected");
+    };
+    assert_eq!(offset, Some((IMG_FAT_OFFSET + IMG_FAT_SIZE) as u64));
+
+    let (vff, _) = VffOptions::new().lenient(true).open(image)?;
+    assert_eq!(
+        vff.borrow().warnings(),
+        [Warning::ReservedEntries {
+            copy: FatCopy::Backup,
+            values: [0, 0]
+        }]
+    );
+
+    let mut img = ImageBuilder::new();
+    img.set_fat(0, 0xff12);
+    assert!(VFF::new(img.build()).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn structure_hash() -> Result<()> {
+    let build = |contents: &[u8]| {
+        let mut img = ImageBuilder::new();
+        let dir = img.add_dir(None, "2022");
+        img.add_file(Some(dir), "PLAY", "LOG", contents);
+        img
+    };
+    let hash = |img: ImageBuilder| VFF::new(img.build())?.0.borrow().structure_hash();
+
+    let first = hash(build(&[1; 0x300]))?;
+    assert_eq!(first, hash(build(&[2; 0x300]))?);
+    assert_ne!(first, hash(build(&[1; 0x100]))?);
+    let mut img = build(&[1; 0x300]);
+    img.data[4] = 1;
+    assert_ne!(first, hash(img)?);
+    // Pinned so an accidental change to the hash shows up
+    assert_eq!(hash(ImageBuilder::new())?, 0xc4faa8c1ce08bdd4);
+    Ok(())
+}
+
+#[test]
+pub fn lazy_fat() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "CDB~1", "CON", b"conf");
+    let image = img.build();
+    let options = VffOptions::new().lazy_fat(true);
+    let (vff, root_dir) = options.open(image.clone())?;
+    assert!(!vff.borrow().fat_loaded());
+    let entries = root_dir.entries(false, false)?;
+    assert_eq!(entries[0].name(), "CDB~1");
+    assert!(!vff.borrow().fat_loaded());
+    assert_eq!(entries[0].load_bytes()?, b"conf");
+    assert!(vff.borrow().fat_loaded());
+    assert_eq!(
+        vff.borrow().free_space_bytes()?,
+        VFF::new(image)?.0.borrow().free_space_bytes()?
+    );
+
+    // A FAT that doesn't open normally only fails once it's needed
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "CDB~1", "CON", b"conf");
+    img.data[IMG_FAT_OFFSET..IMG_FAT_OFFSET + 4].fill(0);
+    let (vff, root_dir) = options.open(img.build())?;
+    assert_eq!(root_dir.entries(false, false)?.len(), 1);
+    assert!(vff.borrow().fat().is_err());
+    assert!(vff.borrow().backup_fat().is_err());
+    assert!(vff.borrow().fat_map().is_err());
+    assert!(vff.borrow().free_space_bytes().is_err());
+    assert!(root_dir.get_path("CDB~1.CON").is_err());
+    assert!(!vff.borrow().fat_loaded());
+    Ok(())
+}
+
+#[test]
+pub fn truncated_input() -> Result<()> {
+    let data = ImageBuilder::new().build().into_inner();
+    let regions = [
+        (0x8, "header"),
+        (0x10, "preamble"),
+        (IMG_FAT_OFFSET + IMG_FAT_SIZE, "FAT"),
+        (IMG_ROOT_OFFSET + 0x20, "root directory"),
+    ];
+    for (len, region) in regions {
+        match VFF::from_bytes(data[..len].to_vec()) {
+            Err(VFFError::InvalidData { context, .. }) => assert!(context.ends_with(region)),
+            other => panic!("{len:#x} bytes should be rejected, got {other:?}"),
+        }
+    }
+    // Nothing past the root directory is needed to open it
+    assert!(VFF::from_bytes(data[..IMG_DATA_OFFSET].to_vec()).is_ok());
+    Ok(())
+}
+
+#[test]
+pub fn error_offsets() -> Result<()> {
+    let mut data = vec![0xaa; 0x100];
+    let mut bad_magic = ImageBuilder::new().build().into_inner();
+    bad_magic[0] = b'X';
+    data.extend(bad_magic);
+    match VFF::new_at_offset(io::Cursor::new(data), 0x100) {
+        Err(err @ VFFError::InvalidData { offset, .. }) => {
+            assert_eq!(offset, Some(0x100));
+            assert!(err.to_string().contains(" at 0x100:"));
+        }
+        other => panic!("a bad magic should be rejected, got {other:?}"),
+    }
+
+    let mut img = ImageBuilder::new();
+    let start = img.add_file(None, "BROKEN", "BIN", &[1; IMG_CLUSTER_SIZE * 2]);
+    // The second cluster points at a free one instead of ending the chain
+    img.set_fat(start + 1, 0);
+    let (vff, _) = VFF::new(img.build())?;
+    match vff.borrow_mut().read_chain(start.into()) {
+        Err(VFFError::InvalidData { offset, .. }) => {
+            assert_eq!(
+                offset,
+                Some((IMG_FAT_OFFSET + 2 * (start as usize + 1)) as u64)
+            )
+        }
+        other => panic!("a broken chain should be rejected, got {other:?}"),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sha2")]
+#[test]
+pub fn sha256_hashes() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let dir = img.add_dir(None, "2022");
+    img.add_file(Some(dir), "ABC", "TXT", b"abc");
+    img.add_file(None, "EMPTY", "BIN", b"");
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    let file = root_dir.get_path("2022/ABC.TXT")?;
+    assert_eq!(hex(&file.sha256()?), abc);
+
+    let mut manifest = Vec::new();
+    root_dir.write_sha256_manifest(&mut manifest, false, &|_| true)?;
+    assert_eq!(
+        String::from_utf8(manifest).unwrap(),
+        format!("{abc}  2022/ABC.TXT\n{empty}  EMPTY.BIN\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn root_dir_size() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_file(None, "CDB~1", "CON", &[9; 4]);
+    let data = img.build().into_inner();
+    let (vff, root_dir) = VFF::from_bytes(data.clone())?;
+    assert_eq!(vff.borrow().root_dir_size(), DEFAULT_ROOT_DIR_SIZE);
+    assert_eq!(root_dir.ls(false)?, ["/CDB~1.CON [0x0004]"]);
+
+    // Drop the unused back half of the root directory
+    let mut small = data;
+    small.drain(IMG_ROOT_OFFSET + 0x800..IMG_DATA_OFFSET);
+    let (vff, root_dir) = VFF::new_with_root_size(io::Cursor::new(small), 0, 0x800)?;
+    assert_eq!(vff.borrow().root_dir_size(), 0x800);
+    assert_eq!(root_dir.get_path("CDB~1.CON")?.load_bytes()?, [9; 4]);
+    assert!(VFF::new_with_root_size(io::Cursor::new(Vec::new()), 0, 0x30).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn walk_iter() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    let year = img.add_dir(None, "2022");
+    let month = img.add_dir(Some(year), "10");
+    img.add_file(Some(month), "2B06C4C3", "000", &[7; 0x20]);
+    img.add_dir(Some(year), "11");
+    let mut gone = ImageBuilder::raw_entry("GONE", "TXT", 0x20, 0, 0);
+    gone[0] = 0xe5;
+    img.add_raw(None, gone);
+    img.add_file(None, "CDB~1", "CON", &[0; 4]);
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let paths = |include_deleted| -> Result<Vec<String>> {
+        root_dir
+            .walk(include_deleted)
+            .map(|entry| entry.map(|e| e.path().to_owned() + "/" + e.full_name()))
+            .collect()
+    };
+    assert_eq!(
+        paths(false)?,
+        [
+            "/2022",
+            "/2022/10",
+            "/2022/10/2B06C4C3.000",
+            "/2022/11",
+            "/CDB~1.CON"
+        ]
+    );
+    assert_eq!(paths(true)?.len(), 6);
+    let first_file = root_dir
+        .walk(false)
+        .find(|entry| entry.as_ref().is_ok_and(|e| !e.is_directory()));
+    assert_eq!(first_file.unwrap()?.full_name(), "2B06C4C3.000");
+    Ok(())
+}
+
+#[test]
+pub fn lookup_options() -> Result<()> {
+    let mut img = ImageBuilder::new();
+    img.add_lfn_file(None, "Notes.txt", ("NOTES", "TXT"), b"long");
+    img.add_file(None, "2B06C4C3", "000", b"file");
+    let (_, root_dir) = VFF::new(img.build())?;
+
+    let found = |path, options| -> Result<bool> {
+        Ok(!matches!(
+            root_dir.get_path_with(path, options)?.content(),
+            DirectoryContent::NoContent
+        ))
+    };
+    let sensitive = LookupOptions {
+        case_sensitive: true,
+        ..Default::default()
+    };
+    let exact = LookupOptions {
+        exact: true,
+        ..Default::default()
+    };
+    assert!(found("notes.TXT", LookupOptions::default())?);
+    assert!(found("Notes.txt", sensitive)?);
+    assert!(!found("notes.txt", sensitive)?);
+    assert!(found("NOTES.TXT", sensitive)?);
+    assert!(found("notes.txt", exact)?);
+    let both = LookupOptions {
+        case_sensitive: true,
+        exact: true,
+    };
+    // The short name no longer counts
+    assert!(!found("NOTES.TXT", both)?);
+    assert!(found("Notes.txt", both)?);
+    assert!(found("2b06c4c3.000", exact)?);
+    Ok(())
+}